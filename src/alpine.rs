@@ -1,96 +1,120 @@
+use crate::base::{self, Artifact, BaseSystemProvider, Checksum};
+use crate::chroot::{self, Chroot};
+use crate::fs::{path_to_string, WorkDir};
+use crate::source::{self, Source};
 use crate::{arch::Arch, http};
 use serde::Deserialize;
-use serde_yaml;
-use sha2::{Digest, Sha512};
-use std::fs::File;
-use std::io::{copy, Read, Result as IoResult};
 use std::path::Path;
 
 type Result<T> = core::result::Result<T, Error>;
 
+pub const DEFAULT_MIRROR: &str = "https://dl-cdn.alpinelinux.org/alpine";
+
 macro_rules! err {
     ($($args:expr),+) => {
         return Err(Error::new(format!($($args,)+)))
     };
 }
 
-pub struct BaseSystemDownloader {
-    client: http::Client,
+pub struct Alpine {
+    downloader: BaseSystemDownloader,
+    /// Pinned in the configuration, trusted over the one in the release information.
+    checksum: Option<Checksum>,
 }
 
-impl BaseSystemDownloader {
-    pub fn new(client: http::Client) -> Self {
-        Self { client }
-    }
-
-    pub fn download<P: AsRef<Path>>(&self, architecture: &Arch, destination_path: P) -> Result<()> {
-        Ok(
-            match self.download_impl(architecture, destination_path.as_ref()) {
-                Ok(_) => {}
-                Err(e) => err!(
-                    "Unable to download and verify Alpine base system tarball: {}",
-                    e
-                ),
-            },
-        )
+impl Alpine {
+    pub fn new(downloader: BaseSystemDownloader, checksum: Option<Checksum>) -> Self {
+        Self {
+            downloader,
+            checksum,
+        }
     }
+}
 
-    fn download_impl(&self, a: &Arch, p: &Path) -> Result<()> {
-        let a = get_architecture(a);
-        let version_file = self.download_version_file(a)?;
-        let release_info = parse_release_info(&version_file)?;
-        let downloaded_size = self.download_tarball(a, &release_info.file, p)?;
-        verify_tarball_size(downloaded_size, release_info.size)?;
-        verify_checksum(p, &release_info.sha512)?;
-        Ok({})
+impl BaseSystemProvider for Alpine {
+    fn name(&self) -> &str {
+        "Alpine Linux"
     }
 
-    fn download_version_file(&self, a: &str) -> Result<String> {
-        Ok(match self.download_verion_file_impl(a) {
-            Ok(v) => v,
-            Err(e) => err!("Failed to download version file: {}", e),
+    fn resolve(&self, architecture: &Arch, work_dir: &WorkDir) -> base::Result<Artifact> {
+        let cache_dir = work_dir.state().join("http-cache");
+        let (release_info, source) = self.downloader.resolve(architecture, &cache_dir)?;
+
+        Ok(Artifact {
+            path: work_dir.downloads().join("base.tar.gz"),
+            source: Some(source),
+            size: Some(release_info.size),
+            checksum: match &self.checksum {
+                Some(c) => Some(c.clone()),
+                None => Some(Checksum::Sha512(release_info.sha512)),
+            },
         })
     }
 
-    fn download_verion_file_impl(&self, a: &str) -> http::Result<String> {
-        let url = format!(
-            "https://dl-cdn.alpinelinux.org/alpine/latest-stable/releases/{}/latest-releases.yaml",
-            a
-        );
+    fn install_packages(&self, chroot: &Chroot, packages: &[&str]) -> base::Result<()> {
+        configure_repositories(chroot.path(), &self.downloader.repository_url())?;
+        install_with_apk(chroot, packages)?;
+
+        Ok({})
+    }
+}
+
+pub struct BaseSystemDownloader {
+    client: http::Client,
+    mirror: Source,
+}
 
-        let req = http::GetRequest::new(url)?;
-        let response = self.client.get(req)?.as_text()?;
+impl BaseSystemDownloader {
+    pub fn new(client: http::Client, mirror: Source) -> Self {
+        Self { client, mirror }
+    }
 
-        Ok(response)
+    pub fn resolve(&self, architecture: &Arch, cache_dir: &Path) -> Result<(VersionFile, Source)> {
+        Ok(match self.resolve_impl(architecture, cache_dir) {
+            Ok(v) => v,
+            Err(e) => err!("Unable to resolve Alpine base system tarball: {}", e),
+        })
     }
 
-    fn download_tarball(&self, a: &str, t: &str, p: &Path) -> Result<u64> {
-        let reader = match self.download_tarball_impl(a, t) {
-            Ok(r) => r,
-            Err(e) => err!("Failed to download tarball file: {}", e),
+    fn resolve_impl(&self, a: &Arch, c: &Path) -> Result<(VersionFile, Source)> {
+        let a = get_architecture(a);
+        let version_file = self.download_version_file(a, c)?;
+        let release_info = parse_release_info(&version_file)?;
+        let path = format!("latest-stable/releases/{}/{}", a, release_info.file);
+        let source = match self.mirror.join(&path) {
+            Ok(s) => s,
+            Err(e) => err!("Invalid tarball location: {}", e),
         };
 
-        Ok(match write_tarball(reader, p) {
-            Ok(s) => s,
-            Err(e) => err!("Failed to write tarball file: {}", e),
+        Ok((release_info, source))
+    }
+
+    fn download_version_file(&self, a: &str, c: &Path) -> Result<String> {
+        Ok(match self.download_verion_file_impl(a, c) {
+            Ok(v) => v,
+            Err(e) => err!("Failed to download version file: {}", e),
         })
     }
 
-    fn download_tarball_impl(&self, a: &str, t: &str) -> http::Result<impl Read> {
-        let url = format!(
-            "https://dl-cdn.alpinelinux.org/alpine/latest-stable/releases/{}/{}",
-            a, t
-        );
+    fn download_verion_file_impl(&self, a: &str, c: &Path) -> source::Result<String> {
+        let path = format!("latest-stable/releases/{}/latest-releases.yaml", a);
 
-        let req = http::GetRequest::new(url)?;
-        let response = self.client.get(req)?.as_reader()?;
+        self.mirror
+            .join(&path)?
+            .read_to_string_cached(&self.client, c)
+    }
 
-        Ok(response)
+    /// The chroot environment can't reach a mirror in a local directory of the host.
+    fn repository_url(&self) -> String {
+        match self.mirror.is_file() {
+            true => DEFAULT_MIRROR.to_owned(),
+            false => format!("{}", self.mirror).trim_end_matches('/').to_owned(),
+        }
     }
 }
 
 #[derive(Deserialize)]
-struct VersionFile {
+pub struct VersionFile {
     flavor: String,
     file: String,
     size: u64,
@@ -115,6 +139,12 @@ impl std::fmt::Display for Error {
     }
 }
 
+impl From<Error> for base::Error {
+    fn from(e: Error) -> Self {
+        base::Error::new(format!("{}", e))
+    }
+}
+
 fn parse_release_info(f: &str) -> Result<VersionFile> {
     let vf: Vec<VersionFile> = match serde_yaml::from_str(f) {
         Ok(f) => f,
@@ -130,55 +160,57 @@ fn parse_release_info(f: &str) -> Result<VersionFile> {
     err!("Unable to find `alpine-minirootfs` release in a version file")
 }
 
-fn verify_checksum(p: &Path, c: &str) -> Result<()> {
-    match verify_checksum_impl(p, c) {
-        Ok(_) => Ok({}),
-        Err(e) => err!("Failed to verify downloaded base system tarball: {}", e),
+fn get_architecture(a: &Arch) -> &'static str {
+    match a {
+        Arch::AMD64 => "x86_64",
+        Arch::X86 => "x86",
+        Arch::AARCH64 => "aarch64",
     }
 }
 
-fn verify_checksum_impl(p: &Path, c: &str) -> IoResult<()> {
-    let mut hasher = Sha512::new();
-    let mut file = File::open(p)?;
-
-    copy(&mut file, &mut hasher)?;
-    let actual_checksum = format!("{:x}", hasher.finalize());
-
-    if actual_checksum == c {
-        return Ok({});
+fn configure_repositories(chroot: &Path, mirror: &str) -> Result<()> {
+    let mut repo_path = chroot.to_owned();
+    repo_path.push("etc");
+    repo_path.push("apk");
+    repo_path.push("repositories");
+    let repositories = ["main", "community", "testing"]
+        .iter()
+        .map(|r| format!("{}/edge/{}/\n", mirror, r))
+        .collect::<String>();
+    match std::fs::write(&repo_path, repositories) {
+        Ok(_) => {}
+        Err(e) => err!(
+            "Failed to update `{}` file: {}",
+            path_to_string(repo_path),
+            e
+        ),
     }
 
-    Err(std::io::Error::new(
-        std::io::ErrorKind::Other,
-        format!(
-            "SHA-512 checksum doesn't match. Expected `{}`, got `{}`",
-            c, actual_checksum
-        ),
-    ))
+    Ok({})
 }
 
-fn verify_tarball_size(download_size: u64, expected_size: u64) -> Result<()> {
-    if download_size == expected_size {
-        return Ok({});
-    }
-
-    err!(
-        "Downloaded Gentoo stage3 tarball size mismatch: expected {}, but got {}",
-        expected_size,
-        download_size
-    )
+pub fn install_with_apk(chroot: &Chroot, packages: &[&str]) -> Result<()> {
+    update_repositories(chroot)?;
+    add_packages(chroot, packages)
 }
 
-fn write_tarball(r: impl Read, p: &Path) -> IoResult<u64> {
-    let mut r = r;
-    let mut file = File::create(p)?;
-    copy(&mut r, &mut file)
+fn update_repositories(chroot: &Chroot) -> Result<()> {
+    match chroot::execute(chroot, ["apk", "update"]) {
+        Ok(_) => Ok({}),
+        Err(e) => err!("Failed to update package repositories:\n{}", e),
+    }
 }
 
-fn get_architecture(a: &Arch) -> &'static str {
-    match a {
-        Arch::AMD64 => "x86_64",
-        Arch::X86 => "x86",
-        Arch::AARCH64 => "aarch64",
+fn add_packages(chroot: &Chroot, packages: &[&str]) -> Result<()> {
+    let mut args = vec!["apk", "add"];
+    args.extend_from_slice(packages);
+
+    match chroot::execute(chroot, args) {
+        Ok(_) => Ok({}),
+        Err(e) => err!(
+            "Failed to install `{}` packages:\n{}",
+            packages.join("`, `"),
+            e
+        ),
     }
 }
@@ -1,13 +1,29 @@
 use crate::{
-    alpine::BaseSystemDownloader,
+    alpine::{Alpine, BaseSystemDownloader},
     arch::Arch,
-    extractor::extract,
-    fs::{create_work_dir, path_to_string},
+    base::{self, Artifact, BaseSystemProvider, Checksum, NIX_PACKAGES},
+    chroot::Chroot,
+    config::{self, Config, HostFile, HostFileMode, Size},
+    dns,
+    download::DownloadManager,
+    eval,
+    fs::{copy_dir, create_work_dir, init_work_dir, path_to_string, WorkDir},
+    gc,
+    host_files::{self, HostFiles},
     http,
-    mount::mount_kernel_filesystems,
+    local::LocalRootfs,
+    mount::{bind_mount, mount_kernel_filesystems},
     nix,
+    nix_conf::{self, NixConf},
+    nixos,
+    preflight::check_work_dir,
+    report::{self, Report},
+    source::{self, Source},
+    store::SharedStore,
+    time::{format_utc, now},
 };
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use sys_mount::{Mount, UnmountDrop};
 
 macro_rules! err {
     ($($msg:expr),+) => {
@@ -17,6 +33,15 @@ macro_rules! err {
 
 type Result<T> = core::result::Result<T, Error>;
 
+const NIXPKGS_TARBALL: &str = "nixpkgs.tar.gz";
+
+const GENERATORS_TARBALL: &str = "nixos-generators.tar.gz";
+
+const BUILD_REPORT: &str = "report.yaml";
+
+/// Kept apart from `rootfs`, which has to be empty for a build.
+const GC_ROOTFS: &str = "gc-rootfs";
+
 pub struct Error {
     error: String,
 }
@@ -29,6 +54,52 @@ impl From<http::Error> for Error {
     }
 }
 
+impl From<config::Error> for Error {
+    fn from(e: config::Error) -> Self {
+        Self {
+            error: format!("{}", e),
+        }
+    }
+}
+
+impl From<source::Error> for Error {
+    fn from(e: source::Error) -> Self {
+        Self {
+            error: format!("{}", e),
+        }
+    }
+}
+
+impl From<nix_conf::Error> for Error {
+    fn from(e: nix_conf::Error) -> Self {
+        Self {
+            error: format!("{}", e),
+        }
+    }
+}
+
+impl From<nixos::Error> for Error {
+    fn from(e: nixos::Error) -> Self {
+        Self {
+            error: format!("{}", e),
+        }
+    }
+}
+
+impl From<gc::Error> for Error {
+    fn from(e: gc::Error) -> Self {
+        Self::new(format!("{}", e))
+    }
+}
+
+impl From<base::Error> for Error {
+    fn from(e: base::Error) -> Self {
+        Self {
+            error: format!("{}", e),
+        }
+    }
+}
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "{}", self.error)
@@ -43,20 +114,362 @@ impl Error {
     }
 }
 
-pub fn init_app() -> Result<App> {
-    let client_builder = http::Client::builder()
-        .request_timeout(None)
-        .connect_timeout(None);
-    let bsd = BaseSystemDownloader::new(client_builder.build()?);
+pub fn init_app(config: Config) -> Result<App> {
+    let client = http_client(&config.http, config.downloads.max_bandwidth)?;
+
+    let provider: Box<dyn BaseSystemProvider> = match &config.base {
+        config::Base::Alpine { mirror, sha512 } => {
+            let mirror = Source::parse(mirror)?;
+            mirror.check_allowed(config.http.allow_http, sha512.is_some())?;
+            let bsd = BaseSystemDownloader::new(client.clone(), mirror);
+
+            Box::new(Alpine::new(bsd, sha512.clone().map(Checksum::Sha512)))
+        }
+        config::Base::Local {
+            path,
+            sha256,
+            sha512,
+        } => {
+            let checksum = pinned_checksum(sha256, sha512, "a local base system")?;
+
+            Box::new(LocalRootfs::new(path, checksum)?)
+        }
+    };
 
-    let app = App::new(bsd)?;
+    let extras = extra_downloads(&config)?;
+    let pinned = Pinned {
+        nixpkgs: pinned_nixpkgs(&config)?,
+        generators: pinned_generators(&config)?,
+    };
+    if config.flake.is_some() && pinned.nixpkgs.is_some() {
+        err!("nixpkgs can't be pinned when building a flake, its `flake.lock` pins it instead");
+    }
+    if config.flake.is_some()
+        && !matches!(
+            config.generators,
+            config::Generators::Nixpkgs { version: None }
+        )
+    {
+        err!("`nixos-generators` isn't used when building a flake");
+    }
+    if config.flake.is_some() && config.container != config::Container::default() {
+        err!("`container` isn't used when building a flake, the flake configures it instead");
+    }
+    nixos::render(&config.container)?;
+    nixos::check_imports(&config.container)?;
+    let nix_conf = nix_conf(&config)?;
+    let cache_files = binary_cache_files(&config.nix)?;
+    let app = App::new(
+        config,
+        provider,
+        client,
+        extras,
+        pinned,
+        nix_conf,
+        cache_files,
+    )?;
 
     Ok(app)
 }
 
+fn pinned_checksum(
+    sha256: &Option<String>,
+    sha512: &Option<String>,
+    what: &str,
+) -> Result<Option<Checksum>> {
+    Ok(match (sha256, sha512) {
+        (Some(_), Some(_)) => err!("Only one of `sha256` and `sha512` can be set for {}", what),
+        (Some(c), None) => Some(Checksum::Sha256(c.clone())),
+        (None, Some(c)) => Some(Checksum::Sha512(c.clone())),
+        (None, None) => None,
+    })
+}
+
+fn nix_conf(config: &Config) -> Result<NixConf> {
+    let mut conf = NixConf::new();
+    // The chroot environment lacks the namespaces the sandbox relies on
+    conf.set("sandbox", "false");
+    if let Some(limit) = config.downloads.chroot_limit() {
+        // Nix expects the transfer rate in KiB per second
+        conf.set("download-speed", (limit.0 / 1024).max(1).to_string());
+    }
+
+    conf.set_user(&config.nix.settings)?;
+
+    for (i, substituter) in config.nix.substituters.iter().enumerate() {
+        // Local caches are mounted in the chroot environment, their parameters still apply
+        let url = match local_cache(&substituter.url) {
+            Some((_, query)) => format!("file://{}/{}{}", nix::LOCAL_CACHES, i, query),
+            None => substituter.url.clone(),
+        };
+        conf.require("extra-substituters", &[&url]);
+        if let Some(key) = &substituter.public_key {
+            conf.require("extra-trusted-public-keys", &[key]);
+        }
+    }
+    if config.nix.netrc.is_some() {
+        conf.set("netrc-file", nix::NETRC);
+    }
+
+    if config.flake.is_some() {
+        conf.require("experimental-features", &["nix-command", "flakes"]);
+    }
+    if let config::Generators::Flake { .. } = config.generators {
+        conf.require("experimental-features", &["nix-command", "flakes"]);
+    }
+    if config.nix.push.is_some() {
+        conf.require("experimental-features", &["nix-command"]);
+    }
+
+    Ok(conf)
+}
+
+/// Splits `file:///srv/cache?priority=10` into `/srv/cache` and `?priority=10`.
+fn local_cache(url: &str) -> Option<(&str, &str)> {
+    let location = url.strip_prefix("file://")?;
+
+    Some(match location.find('?') {
+        Some(i) => location.split_at(i),
+        None => (location, ""),
+    })
+}
+
+fn binary_cache_files(config: &config::Nix) -> Result<Vec<HostFile>> {
+    let mut files = vec![];
+
+    for (i, substituter) in config.substituters.iter().enumerate() {
+        let path = match local_cache(&substituter.url) {
+            Some((p, _)) => PathBuf::from(p),
+            None => continue,
+        };
+        if !path.is_dir() {
+            err!(
+                "Local binary cache `{}` is not a directory",
+                path_to_string(&path)
+            );
+        }
+
+        files.push(HostFile {
+            path,
+            target: Some(PathBuf::from(format!("{}/{}", nix::LOCAL_CACHES, i))),
+            mode: HostFileMode::Bind,
+        });
+    }
+
+    if let Some(netrc) = &config.netrc {
+        if !netrc.is_file() {
+            err!("`netrc` file `{}` doesn't exist", path_to_string(netrc));
+        }
+
+        files.push(HostFile {
+            path: netrc.clone(),
+            target: Some(PathBuf::from(nix::NETRC)),
+            mode: HostFileMode::Copy,
+        });
+    }
+
+    if let Some(key) = config.push.as_ref().and_then(|p| p.secret_key.as_ref()) {
+        if !key.is_file() {
+            err!("Secret key `{}` doesn't exist", path_to_string(key));
+        }
+
+        files.push(HostFile {
+            path: key.clone(),
+            target: Some(PathBuf::from(nix::SECRET_KEY)),
+            mode: HostFileMode::Copy,
+        });
+    }
+
+    Ok(files)
+}
+
+fn pinned_nixpkgs(config: &Config) -> Result<Option<ExtraDownload>> {
+    let (url, sha256) = match &config.nixpkgs {
+        config::Nixpkgs::Channel { .. } => return Ok(None),
+        config::Nixpkgs::Tarball { url, sha256 } => (url.clone(), sha256),
+        config::Nixpkgs::Commit { commit, sha256 } => {
+            check_commit(commit, "nixpkgs")?;
+
+            (
+                format!("https://github.com/NixOS/nixpkgs/archive/{}.tar.gz", commit),
+                sha256,
+            )
+        }
+    };
+
+    let source = Source::parse(&url)?;
+    source.check_allowed(config.http.allow_http, true)?;
+
+    Ok(Some(ExtraDownload {
+        name: NIXPKGS_TARBALL.to_owned(),
+        source,
+        checksum: Some(Checksum::Sha256(sha256.clone())),
+    }))
+}
+
+fn pinned_generators(config: &Config) -> Result<Option<ExtraDownload>> {
+    let (reference, sha256) = match &config.generators {
+        config::Generators::Nixpkgs { .. } => return Ok(None),
+        config::Generators::Release { version, sha256 } => {
+            check_release(version, "nixos-generators")?;
+
+            (version, sha256)
+        }
+        config::Generators::Commit { commit, sha256, .. } => {
+            check_commit(commit, "nixos-generators")?;
+
+            (commit, sha256)
+        }
+        config::Generators::Flake { commit } => {
+            check_commit(commit, "nixos-generators")?;
+
+            return Ok(None);
+        }
+    };
+
+    let source = Source::parse(&format!(
+        "https://github.com/nix-community/nixos-generators/archive/{}.tar.gz",
+        reference
+    ))?;
+    source.check_allowed(config.http.allow_http, true)?;
+
+    Ok(Some(ExtraDownload {
+        name: GENERATORS_TARBALL.to_owned(),
+        source,
+        checksum: Some(Checksum::Sha256(sha256.clone())),
+    }))
+}
+
+fn check_release(version: &str, repository: &str) -> Result<()> {
+    let valid = version.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ['.', '-', '_', '+'].contains(&c))
+        && !version.contains("..");
+    if !valid {
+        err!("`{}` is not a valid {} release", version, repository);
+    }
+
+    Ok({})
+}
+
+fn check_commit(commit: &str, repository: &str) -> Result<()> {
+    if commit.is_empty() || !commit.chars().all(|c| c.is_ascii_hexdigit()) {
+        err!("`{}` is not a valid {} commit", commit, repository);
+    }
+
+    Ok({})
+}
+
+/// Tarballs of the repository record no version, but a pinned one was verified by its SHA-256.
+fn verify_generators_version(
+    expected: Option<&str>,
+    installed: &str,
+    pinned: bool,
+) -> Result<String> {
+    match expected {
+        None => Ok(format!("`nixos-generators` version is `{}`", installed)),
+        Some(e) if installed == nix::UNKNOWN_VERSION && pinned => Ok(format!(
+            "`nixos-generators` doesn't record its version, the tarball pinned to `{}` was verified by its SHA-256 instead",
+            e
+        )),
+        Some(e) if e.trim_start_matches('v') != installed.trim_start_matches('v') => err!(
+            "`nixos-generators` version `{}` was installed, but `{}` is expected",
+            installed,
+            e
+        ),
+        Some(_) => Ok(format!(
+            "`nixos-generators` version is `{}` as expected",
+            installed
+        )),
+    }
+}
+
+struct Pinned {
+    nixpkgs: Option<ExtraDownload>,
+    generators: Option<ExtraDownload>,
+}
+
+struct ExtraDownload {
+    name: String,
+    source: Source,
+    checksum: Option<Checksum>,
+}
+
+fn extra_downloads(config: &Config) -> Result<Vec<ExtraDownload>> {
+    let mut extras: Vec<ExtraDownload> = vec![];
+
+    for extra in &config.downloads.extra {
+        let source = Source::parse(&extra.url)?;
+        let checksum = pinned_checksum(&extra.sha256, &extra.sha512, &format!("`{}`", extra.url))?;
+        source.check_allowed(config.http.allow_http, checksum.is_some())?;
+
+        let name = match &extra.name {
+            Some(n) => n.clone(),
+            None => extra
+                .url
+                .trim_end_matches('/')
+                .rsplit('/')
+                .next()
+                .unwrap_or_default()
+                .to_owned(),
+        };
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            err!("`{}` is not a valid file name for `{}`", name, extra.url);
+        }
+        if extras.iter().any(|e| e.name == name) {
+            err!(
+                "`{}` is downloaded more than once, set a different `name`",
+                name
+            );
+        }
+
+        extras.push(ExtraDownload {
+            name,
+            source,
+            checksum,
+        });
+    }
+
+    Ok(extras)
+}
+
+fn http_client(config: &config::Http, max_bandwidth: Size) -> Result<http::Client> {
+    let mut client_builder = http::Client::builder()
+        .request_timeout(None)
+        .connect_timeout(None)
+        .env_proxy(config.env_proxy)
+        .allow_http(config.allow_http)
+        .rate_limit(max_bandwidth.0);
+
+    for path in &config.ca_certificates {
+        let pem = match std::fs::read(path) {
+            Ok(p) => p,
+            Err(e) => err!(
+                "Unable to read CA certificates `{}`: {}",
+                path_to_string(path),
+                e
+            ),
+        };
+        client_builder = client_builder.add_root_certificates(&pem)?;
+    }
+
+    if let Some(proxy) = &config.proxy {
+        client_builder = client_builder.proxy(proxy.as_str(), &config.no_proxy)?;
+    }
+
+    Ok(client_builder.build()?)
+}
+
 pub struct App {
     arch: Arch,
-    bsd: BaseSystemDownloader,
+    config: Config,
+    provider: Box<dyn BaseSystemProvider>,
+    client: http::Client,
+    extras: Vec<ExtraDownload>,
+    pinned: Pinned,
+    nix_conf: NixConf,
+    cache_files: Vec<HostFile>,
 }
 
 impl Drop for App {
@@ -67,97 +480,715 @@ impl Drop for App {
 }
 
 impl App {
-    pub fn new(base_system_downloader: BaseSystemDownloader) -> Result<Self> {
+    fn new(
+        config: Config,
+        provider: Box<dyn BaseSystemProvider>,
+        client: http::Client,
+        extras: Vec<ExtraDownload>,
+        pinned: Pinned,
+        nix_conf: NixConf,
+        cache_files: Vec<HostFile>,
+    ) -> Result<Self> {
         check_platform()?;
         let arch = get_architecture()?;
 
         Ok(Self {
-            arch: arch,
-            bsd: base_system_downloader,
+            arch,
+            config,
+            provider,
+            client,
+            extras,
+            pinned,
+            nix_conf,
+            cache_files,
         })
     }
 
     pub fn build(&self) -> Result<()> {
-        // TODO: parse arguments
-        // TODO: parse config
+        let started = format_utc(now());
 
-        let wd = std::path::PathBuf::from("./workdir/");
         println!("Creating working directory...");
-        match create_work_dir(&wd) {
-            Ok(_) => println!("... OK: `{}` was successfully created", path_to_string(&wd)),
+        let wd = match create_work_dir(&self.config.work_dir) {
+            Ok(wd) => {
+                println!(
+                    "... OK: `{}` was successfully created and locked",
+                    path_to_string(wd.root())
+                );
+                if let Some(owner) = wd.lock().stale_owner() {
+                    println!(
+                        "... NOTE: cleared stale lock `{}` left by {}",
+                        path_to_string(wd.lock().path()),
+                        owner
+                    );
+                }
+
+                wd
+            }
+            Err(e) => err!("... ERROR: {}", e),
+        };
+
+        let store = self.open_store()?;
+
+        println!("Running pre-flight checks...");
+        let findings = check_work_dir(wd.root(), &self.config.preflight);
+        for finding in &findings {
+            match finding.passed {
+                true => println!("... OK: {}", finding.message),
+                false => println!("... ERROR: {}", finding.message),
+            }
+        }
+        if findings.iter().any(|f| !f.passed) {
+            err!("Pre-flight checks failed, see above for details");
+        }
+
+        let extras: Vec<Artifact> = self
+            .extras
+            .iter()
+            .chain(&self.pinned.nixpkgs)
+            .chain(&self.pinned.generators)
+            .map(|e| Artifact {
+                path: wd.downloads().join(&e.name),
+                source: Some(e.source.clone()),
+                size: None,
+                checksum: e.checksum.clone(),
+            })
+            .collect();
+        let artifact = self.fetch_base_system(&wd, &extras)?;
+
+        let rootfs = wd.rootfs();
+        self.extract_base_system(&artifact, &rootfs)?;
+
+        let resolv_conf = self.configure_dns(&rootfs)?;
+
+        let host_files = self.install_host_files(&rootfs)?;
+
+        let _store_mount = match &store {
+            Some(store) => {
+                println!("Mounting shared Nix store...");
+                match store.mount(&rootfs) {
+                    Ok(m) => {
+                        println!(
+                            "... OK: `{}` was mounted as `/nix`",
+                            path_to_string(store.path())
+                        );
+
+                        Some(m)
+                    }
+                    Err(e) => err!("... ERROR: {}", e),
+                }
+            }
+            None => None,
+        };
+
+        let _mounts = mount_kernel(&rootfs)?;
+
+        let channel = match (&self.config.flake, &self.config.nixpkgs) {
+            (None, config::Nixpkgs::Channel { name }) => Some(name.as_str()),
+            _ => None,
+        };
+
+        let mut chroot = Chroot::new(&rootfs);
+        if self.pinned.nixpkgs.is_some() {
+            chroot = chroot.env("NIX_PATH", format!("nixpkgs={}", nix::PINNED_NIXPKGS));
+        }
+        for (key, value) in &self.config.environment {
+            chroot = chroot.env(key, value);
+        }
+
+        self.install_nix(&chroot)?;
+
+        if self.pinned.nixpkgs.is_some() {
+            println!("Unpacking pinned nixpkgs...");
+            let tarball = wd.downloads().join(NIXPKGS_TARBALL);
+            match nix::unpack_expressions(&chroot, &tarball, nix::PINNED_NIXPKGS) {
+                Ok(_) => println!(
+                    "... OK: nixpkgs was successfully unpacked into `{}`",
+                    nix::PINNED_NIXPKGS
+                ),
+                Err(e) => err!("... ERROR: {}", e),
+            }
+        }
+
+        if self.pinned.generators.is_some() {
+            println!("Unpacking pinned `nixos-generators`...");
+            let tarball = wd.downloads().join(GENERATORS_TARBALL);
+            match nix::unpack_expressions(&chroot, &tarball, nix::PINNED_GENERATORS) {
+                Ok(_) => println!(
+                    "... OK: `nixos-generators` was successfully unpacked into `{}`",
+                    nix::PINNED_GENERATORS
+                ),
+                Err(e) => err!("... ERROR: {}", e),
+            }
+        }
+
+        let cache_files = match self.cache_files.is_empty() {
+            true => HostFiles::default(),
+            false => {
+                println!("Making binary caches available in the chroot environment...");
+                let cache_files = match host_files::install(&rootfs, &self.cache_files) {
+                    Ok(c) => c,
+                    Err(e) => err!("... ERROR: {}", e),
+                };
+                if self.config.nix.netrc.is_some() {
+                    if let Err(e) = nix::restrict_secret(&chroot, nix::NETRC) {
+                        err!("... ERROR: {}", e);
+                    }
+                }
+                if let Some(config::Push {
+                    secret_key: Some(_),
+                    ..
+                }) = &self.config.nix.push
+                {
+                    if let Err(e) = nix::restrict_secret(&chroot, nix::SECRET_KEY) {
+                        err!("... ERROR: {}", e);
+                    }
+                }
+                println!("... OK: local binary caches were mounted and credentials were copied");
+
+                cache_files
+            }
+        };
+
+        println!("Configuring Nix package manager...");
+        match nix::setup_nix(&chroot, &self.nix_conf, channel) {
+            Ok(profile) => {
+                println!(
+                    "... OK: Nix package manager was successfully configured, {}",
+                    profile
+                );
+            }
+            Err(e) => err!("... ERROR: {}", e),
+        }
+
+        let (nixpkgs, generators, image) = match &self.config.flake {
+            Some(flake) => {
+                let (nixpkgs, image) = self.build_flake(&chroot, &wd, flake)?;
+
+                (nixpkgs, None, image)
+            }
+            None => {
+                self.check_configuration(&chroot, &wd)?;
+                let (nixpkgs, generators, generator) = self.install_generators(&chroot, channel)?;
+                let image = self.generate_image(&chroot, &wd, &generator)?;
+
+                (nixpkgs, Some(generators), image)
+            }
+        };
+
+        if let Some(push) = &self.config.nix.push {
+            self.push_closure(&chroot, push, nix::RESULT)?;
+        }
+
+        println!("Removing host files from the chroot environment...");
+        if let Err(e) = cache_files.remove().and_then(|_| host_files.remove()) {
+            err!("... ERROR: {}", e);
+        }
+        match nixos::remove_imports(&chroot) {
+            Ok(_) => println!("... OK: host files, credentials and imported modules were removed"),
             Err(e) => err!("... ERROR: {}", e),
+        }
+
+        if let Some(resolv_conf) = resolv_conf {
+            println!("Restoring original DNS configuration in the chroot environment...");
+            match resolv_conf.restore() {
+                Ok(_) => println!("... OK: original resolv.conf was restored"),
+                Err(e) => err!("... ERROR: {}", e),
+            }
+        }
+
+        let report = Report {
+            started,
+            architecture: std::env::consts::ARCH.to_owned(),
+            base_system: self.provider.name().to_owned(),
+            nixpkgs,
+            flake: self.config.flake.as_ref().map(|f| f.output.clone()),
+            generators,
+            image: Some(path_to_string(image)),
         };
 
-        let mut tarball_path = wd.clone();
-        tarball_path.push("base.txz");
-        println!("Downloading base system tarball...");
-        match self.bsd.download(&self.arch, &tarball_path) {
+        let report_path = wd.output().join(BUILD_REPORT);
+        println!("Writing build report...");
+        match report.write(&report_path) {
             Ok(_) => println!(
-                "... OK: `{}` was successfully downloaded",
-                path_to_string(&tarball_path)
+                "... OK: `{}` was successfully written",
+                path_to_string(&report_path)
             ),
             Err(e) => err!("... ERROR: {}", e),
+        }
+
+        // TODO:
+        // run clean-up:
+        // - Unmount
+        // - remove destination directory
+        // upload tarball image to Proxmox
+        // create container using uploaded image
+        // - figure out how to specify various parameters
+        // figure out how to tweak LXC image to contain necessary tools
+
+        Ok({})
+    }
+
+    /// The store only contains Nix if Nix installed it, so gc runs in a base system of its own.
+    pub fn gc(&self) -> Result<()> {
+        if self.config.nix.gc.max_age_days.is_none() && self.config.nix.gc.max_size.is_none() {
+            err!("Neither `nix.gc.max_age_days` nor `nix.gc.max_size` is configured");
+        }
+        let store = match self.open_store()? {
+            Some(s) => s,
+            None => err!("There is no shared Nix store, `nix.store` isn't configured"),
+        };
+
+        println!("Opening working directory...");
+        let wd = match init_work_dir(&self.config.work_dir) {
+            Ok(wd) => {
+                println!(
+                    "... OK: `{}` was successfully locked",
+                    path_to_string(wd.root())
+                );
+
+                wd
+            }
+            Err(e) => err!("... ERROR: {}", e),
+        };
+
+        let rootfs = self.gc_rootfs(&wd)?;
+
+        Ok(gc::run(&self.config, &store, &rootfs)?)
+    }
+
+    fn gc_rootfs(&self, wd: &WorkDir) -> Result<PathBuf> {
+        let rootfs = wd.state().join(GC_ROOTFS);
+        let installed = ["nix-env", "nix-store"]
+            .iter()
+            .all(|b| rootfs.join("usr/bin").join(b).symlink_metadata().is_ok());
+        if installed {
+            return Ok(rootfs);
+        }
+        // Mounts of an interrupted setup may still be in place, so it isn't removed blindly
+        if rootfs.exists() {
+            err!(
+                "`{}` is left over from an interrupted setup and has to be removed",
+                path_to_string(&rootfs)
+            );
+        }
+
+        let artifact = self.fetch_base_system(wd, &[])?;
+        self.extract_base_system(&artifact, &rootfs)?;
+        let _resolv_conf = self.configure_dns(&rootfs)?;
+        let _host_files = self.install_host_files(&rootfs)?;
+        let _mounts = mount_kernel(&rootfs)?;
+
+        let mut chroot = Chroot::new(&rootfs);
+        for (key, value) in &self.config.environment {
+            chroot = chroot.env(key, value);
+        }
+        self.install_nix(&chroot)?;
+
+        Ok(rootfs)
+    }
+
+    fn open_store(&self) -> Result<Option<SharedStore>> {
+        Ok(match &self.config.nix.store {
+            Some(path) => {
+                println!("Opening shared Nix store...");
+                match SharedStore::open(path) {
+                    Ok(s) => {
+                        println!(
+                            "... OK: `{}` was successfully locked",
+                            path_to_string(s.path())
+                        );
+                        if let Some(owner) = s.lock().stale_owner() {
+                            println!(
+                                "... NOTE: cleared stale lock `{}` left by {}",
+                                path_to_string(s.lock().path()),
+                                owner
+                            );
+                        }
+
+                        Some(s)
+                    }
+                    Err(e) => err!("... ERROR: {}", e),
+                }
+            }
+            None => None,
+        })
+    }
+
+    fn fetch_base_system(&self, wd: &WorkDir, extras: &[Artifact]) -> Result<Artifact> {
+        println!("Resolving {} base system...", self.provider.name());
+        let artifact = match self.provider.resolve(&self.arch, wd) {
+            Ok(a) => {
+                match &a.source {
+                    Some(s) => println!(
+                        "... OK: `{}` is downloaded from `{}`",
+                        path_to_string(&a.path),
+                        s
+                    ),
+                    None => println!("... OK: `{}` is used as is", path_to_string(&a.path)),
+                }
+
+                a
+            }
+            Err(e) => err!("... ERROR: {}", e),
         };
 
-        println!("Extracting base system tarball...");
-        match extract(&tarball_path) {
+        let downloads: Vec<&Artifact> = std::iter::once(&artifact)
+            .chain(extras)
+            .filter(|a| a.source.is_some())
+            .collect();
+        for (i, download) in downloads.iter().enumerate() {
+            if downloads[..i].iter().any(|d| d.path == download.path) {
+                err!(
+                    "`{}` is downloaded more than once, set a different `name`",
+                    path_to_string(&download.path)
+                );
+            }
+        }
+        if !downloads.is_empty() {
+            println!("Downloading {} artifacts...", downloads.len());
+            let manager = DownloadManager::new(&self.client, self.config.downloads.concurrency);
+
+            match manager.run(&downloads) {
+                Ok(bytes) => println!(
+                    "... OK: {} were successfully downloaded and verified",
+                    Size(bytes)
+                ),
+                Err(e) => err!("... ERROR: {}", e),
+            }
+        }
+
+        println!("Verifying base system...");
+        match self.provider.verify(&artifact) {
             Ok(_) => println!(
-                "... OK: `{}` was successfully extracted",
-                path_to_string(&tarball_path)
+                "... OK: `{}` was successfully verified",
+                path_to_string(&artifact.path)
+            ),
+            Err(e) => err!("... ERROR: {}", e),
+        }
+
+        Ok(artifact)
+    }
+
+    fn extract_base_system(&self, artifact: &Artifact, rootfs: &Path) -> Result<()> {
+        println!("Extracting base system...");
+        match self.provider.extract(artifact, rootfs) {
+            Ok(summary) => println!(
+                "... OK: `{}` was successfully extracted into `{}` ({})",
+                path_to_string(&artifact.path),
+                path_to_string(rootfs),
+                summary
             ),
             Err(e) => err!("... ERROR: {}", e),
         }
 
+        Ok({})
+    }
+
+    fn configure_dns(&self, rootfs: &Path) -> Result<Option<dns::ResolvConf>> {
         println!("Configure DNS resolution in the chroot environment...");
-        match fix_resolv_conf(&wd) {
-            Ok(_) => {
-                println!("... OK: successfully created resolv.conf");
+        Ok(match dns::configure(rootfs, &self.config.dns) {
+            Ok(r) => {
+                println!("... OK: {}", r.description());
+
+                Some(r)
             }
+            Err(e) if self.config.dns.required => err!("... ERROR: {}", e),
             Err(e) => {
                 eprintln!("... ERROR: {}", e);
+
+                None
             }
-        }
+        })
+    }
 
-        println!("Mounting Virtual Kernel File Systems...");
-        let _mounts = match mount_kernel_filesystems(&wd) {
-            Ok(mts) => {
-                println!("... OK: devtmpfs, procfs, sysfs were successfully mounted");
+    fn install_host_files(&self, rootfs: &Path) -> Result<HostFiles> {
+        println!("Making host files available in the chroot environment...");
+        Ok(match host_files::install(rootfs, &self.config.host_files) {
+            Ok(h) => {
+                println!(
+                    "... OK: {} host files were copied or mounted",
+                    self.config.host_files.len()
+                );
 
-                mts
+                h
             }
             Err(e) => err!("... ERROR: {}", e),
-        };
+        })
+    }
 
+    fn install_nix(&self, chroot: &Chroot) -> Result<()> {
         println!("Installing Nix package manager...");
-        match nix::install_nix(&wd) {
+        // Nix honours a limit, but neither `apk` nor the other package managers offer one
+        if self.config.downloads.chroot_limit().is_some() {
+            println!(
+                "... NOTE: `downloads.max_bandwidth` doesn't apply to the package manager of the {} base system, only to Nix",
+                self.provider.name()
+            );
+        }
+        match self.provider.install_packages(chroot, &NIX_PACKAGES) {
             Ok(_) => {
                 println!("... OK: Nix package manager was succefully installed");
             }
             Err(e) => err!("... ERROR: {}", e),
         }
 
+        Ok({})
+    }
+
+    fn push_closure(&self, chroot: &Chroot, push: &config::Push, installable: &str) -> Result<()> {
+        println!("Pushing closure to binary cache...");
+        let (url, _mount) = match local_cache(&push.url) {
+            Some((path, query)) => {
+                let target = chroot.path().join(nix::PUSH_CACHE.trim_start_matches('/'));
+                let mounted = std::fs::create_dir_all(path)
+                    .and_then(|_| std::fs::create_dir_all(&target))
+                    .and_then(|_| bind_mount(path, &target, false));
+                match mounted {
+                    Ok(m) => (format!("file://{}{}", nix::PUSH_CACHE, query), Some(m)),
+                    Err(e) => err!("... ERROR: Unable to mount `{}`: {}", path, e),
+                }
+            }
+            None => (push.url.clone(), None),
+        };
+
+        match nix::push_closure(chroot, installable, &url, push.secret_key.is_some()) {
+            Ok(_) => println!(
+                "... OK: closure of `{}` was successfully pushed to `{}`",
+                installable, push.url
+            ),
+            Err(e) => err!("... ERROR: {}", e),
+        }
+
+        Ok({})
+    }
+
+    fn install_generators(
+        &self,
+        chroot: &Chroot,
+        channel: Option<&str>,
+    ) -> Result<(report::Nixpkgs, report::Generators, nix::Generator)> {
+        let (generators, generator) = match &self.config.generators {
+            config::Generators::Flake { commit } => {
+                let generator = nix::Generator::flake(commit);
+                println!("Preparing `nixos-generators` flake...");
+                match generator.prepare(chroot) {
+                    Ok(_) => println!("... OK: `nixos-generators` flake was successfully built"),
+                    Err(e) => err!("... ERROR: {}", e),
+                }
+
+                let generators = report::Generators {
+                    source: format!("{}", generator),
+                    version: commit.clone(),
+                };
+
+                (generators, generator)
+            }
+            _ => (
+                self.install_pinned_generators(chroot, channel)?,
+                nix::Generator::Installed,
+            ),
+        };
+
+        println!("Determining nixpkgs revision...");
+        let revision = match &self.config.nixpkgs {
+            config::Nixpkgs::Commit { commit, .. } => Ok(commit.clone()),
+            _ => nix::nixpkgs_revision(chroot),
+        };
+        let revision = match revision {
+            Ok(r) => {
+                println!("... OK: nixpkgs revision is `{}`", r);
+
+                r
+            }
+            Err(e) => err!("... ERROR: {}", e),
+        };
+
+        let nixpkgs = report::Nixpkgs {
+            source: match &self.pinned.nixpkgs {
+                Some(n) => format!("{}", n.source),
+                None => format!("https://nixos.org/channels/{}", channel.unwrap_or_default()),
+            },
+            revision,
+            sha256: match &self.config.nixpkgs {
+                config::Nixpkgs::Channel { .. } => None,
+                config::Nixpkgs::Tarball { sha256, .. }
+                | config::Nixpkgs::Commit { sha256, .. } => Some(sha256.clone()),
+            },
+        };
+
+        Ok((nixpkgs, generators, generator))
+    }
+
+    fn install_pinned_generators(
+        &self,
+        chroot: &Chroot,
+        channel: Option<&str>,
+    ) -> Result<report::Generators> {
+        let (from, source) = match (&self.pinned.generators, &self.pinned.nixpkgs) {
+            (Some(g), _) => (nix::GeneratorsFrom::Pinned, format!("{}", g.source)),
+            (None, Some(n)) => (nix::GeneratorsFrom::PinnedNixpkgs, format!("{}", n.source)),
+            (None, None) => (
+                nix::GeneratorsFrom::Channel,
+                format!("https://nixos.org/channels/{}", channel.unwrap_or_default()),
+            ),
+        };
+
         println!("Installing the `nixos-generators` package using Nix...");
-        match nix::install_nixos_generators(&wd) {
+        match nix::install_nixos_generators(chroot, from) {
             Ok(_) => {
                 println!("... OK: `nixos-generators` package was successfully installed");
             }
             Err(e) => err!("... ERROR: {}", e),
         }
 
-        // TODO:
-        // from chroot:
-        // - Run `nixos-generate -f lxc`
-        // - Copy resulting tarball image to the destination
-        // run clean-up:
-        // - Unmount
-        // - remove destination directory
-        // upload tarball image to Proxmox
-        // create container using uploaded image
-        // - figure out how to specify various parameters
-        // figure out how to tweak LXC image to contain necessary tools
+        let (expected, reference) = match &self.config.generators {
+            config::Generators::Nixpkgs { version } => (version.as_deref(), None),
+            config::Generators::Release { version, .. } => (Some(version.as_str()), Some(version)),
+            config::Generators::Commit {
+                commit, version, ..
+            } => (version.as_deref(), Some(version.as_ref().unwrap_or(commit))),
+            config::Generators::Flake { .. } => (None, None),
+        };
+
+        println!("Verifying `nixos-generators` version...");
+        let mut version = match nix::nixos_generators_version(chroot) {
+            Ok(v) => v,
+            Err(e) => err!("... ERROR: {}", e),
+        };
+        let pinned = self.pinned.generators.is_some();
+        match verify_generators_version(expected, &version, pinned) {
+            Ok(m) => println!("... OK: {}", m),
+            Err(e) => err!("... ERROR: {}", e),
+        }
+        // The tarball is pinned, so the version it was pinned to is the one to record
+        if let (true, Some(r)) = (version == nix::UNKNOWN_VERSION && pinned, reference) {
+            version = r.clone();
+        }
+
+        Ok(report::Generators { source, version })
+    }
+
+    fn check_configuration(&self, chroot: &Chroot, wd: &WorkDir) -> Result<()> {
+        println!("Writing NixOS configuration...");
+        match nixos::install(chroot, &self.config.container, wd.root()) {
+            Ok(_) => println!(
+                "... OK: `{}` was successfully written along with {} imported modules",
+                nixos::CONFIGURATION,
+                self.config.container.imports.len()
+            ),
+            Err(e) => err!("... ERROR: {}", e),
+        }
+
+        println!("Evaluating NixOS configuration...");
+        let remaps = nixos::import_locations(&self.config.container);
+        match eval::check_configuration(chroot, &remaps) {
+            Ok(_) => println!("... OK: NixOS configuration was successfully evaluated"),
+            Err(e) => err!("... ERROR: {}", e),
+        }
 
         Ok({})
     }
+
+    fn generate_image(
+        &self,
+        chroot: &Chroot,
+        wd: &WorkDir,
+        generator: &nix::Generator,
+    ) -> Result<PathBuf> {
+        println!("Generating LXC image...");
+        let result = match nix::generate_lxc_image(chroot, generator) {
+            Ok(r) => {
+                println!(
+                    "... OK: `{}` was successfully generated",
+                    path_to_string(&r)
+                );
+
+                r
+            }
+            Err(e) => err!("... ERROR: {}", e),
+        };
+
+        copy_image(wd, &result)
+    }
+
+    fn build_flake(
+        &self,
+        chroot: &Chroot,
+        wd: &WorkDir,
+        flake: &config::Flake,
+    ) -> Result<(report::Nixpkgs, PathBuf)> {
+        println!("Copying flake into the chroot environment...");
+        match nix::copy_flake(chroot, &flake.path) {
+            Ok(_) => println!(
+                "... OK: `{}` was successfully copied into `{}`",
+                path_to_string(&flake.path),
+                nix::FLAKE_DIR
+            ),
+            Err(e) => err!("... ERROR: {}", e),
+        }
+
+        println!("Evaluating `{}` flake output...", flake.output);
+        match eval::check_flake(chroot, nix::FLAKE_DIR, &flake.output, flake.path.clone()) {
+            Ok(_) => println!("... OK: `{}` was successfully evaluated", flake.output),
+            Err(e) => err!("... ERROR: {}", e),
+        }
+
+        let revision = match nix::flake_nixpkgs_revision(&flake.path) {
+            Ok(r) => r,
+            Err(e) => err!("... ERROR: {}", e),
+        };
+
+        println!("Building `{}` flake output...", flake.output);
+        let result = match nix::build_flake(chroot, &flake.output) {
+            Ok(r) => {
+                println!("... OK: `{}` was successfully built", path_to_string(&r));
+
+                r
+            }
+            Err(e) => err!("... ERROR: {}", e),
+        };
+
+        let image = copy_image(wd, &result)?;
+
+        let nixpkgs = report::Nixpkgs {
+            source: path_to_string(flake.path.join("flake.lock")),
+            revision,
+            sha256: None,
+        };
+
+        Ok((nixpkgs, image))
+    }
+}
+
+fn copy_image(wd: &WorkDir, result: &Path) -> Result<PathBuf> {
+    let image = wd.output().join(result.file_name().unwrap_or_default());
+    println!("Copying image into the output directory...");
+    let copied = match result.is_dir() {
+        true => std::fs::create_dir_all(&image).and_then(|_| copy_dir(result, &image)),
+        false => std::fs::copy(result, &image).map(|_| {}),
+    };
+    match copied {
+        Ok(_) => println!(
+            "... OK: `{}` was successfully copied into `{}`",
+            path_to_string(result),
+            path_to_string(&image)
+        ),
+        Err(e) => err!("... ERROR: {}", e),
+    }
+
+    Ok(image)
+}
+
+fn mount_kernel(rootfs: &Path) -> Result<Vec<UnmountDrop<Mount>>> {
+    println!("Mounting Virtual Kernel File Systems...");
+    Ok(match mount_kernel_filesystems(rootfs) {
+        Ok(mts) => {
+            println!("... OK: devtmpfs, procfs, sysfs were successfully mounted");
+
+            mts
+        }
+        Err(e) => err!("... ERROR: {}", e),
+    })
 }
 
 fn check_platform() -> Result<()> {
@@ -181,17 +1212,134 @@ fn get_architecture() -> Result<Arch> {
     }
 }
 
-fn fix_resolv_conf(p: &Path) -> Result<()> {
-    let mut resolv_conf_path = p.to_owned();
-    resolv_conf_path.push("etc");
-    resolv_conf_path.push("resolv.conf");
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    match std::fs::write(&resolv_conf_path, "nameserver 8.8.8.8") {
-        Ok(_) => Ok({}),
-        Err(e) => err!(
-            "Unable to create `{}` file: {}",
-            path_to_string(&resolv_conf_path),
-            e
-        ),
+    fn config(yaml: &str) -> Config {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn splits_local_cache_urls() {
+        assert_eq!(
+            local_cache("file:///srv/cache?priority=10&trusted=1"),
+            Some(("/srv/cache", "?priority=10&trusted=1"))
+        );
+        assert_eq!(local_cache("file:///srv/cache"), Some(("/srv/cache", "")));
+        assert_eq!(local_cache("https://cache.example.org?priority=10"), None);
+    }
+
+    #[test]
+    fn mounts_local_substituters_with_their_parameters() {
+        let config = config(
+            "nix:\n  substituters:\n    - url: https://cache.example.org\n      public_key: cache.example.org-1:abc\n    - url: file:///srv/cache?priority=10\n",
+        );
+
+        let conf = nix_conf(&config).ok().unwrap().render();
+        assert!(conf.contains(&format!(
+            "extra-substituters = https://cache.example.org file://{}/1?priority=10\n",
+            nix::LOCAL_CACHES
+        )));
+        assert!(conf.contains("extra-trusted-public-keys = cache.example.org-1:abc\n"));
+    }
+
+    #[test]
+    fn requires_local_caches_to_exist() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&format!(
+            "nix:\n  substituters:\n    - url: file://{}?priority=10\n",
+            path_to_string(dir.path())
+        ));
+
+        let files = binary_cache_files(&config.nix).ok().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, dir.path());
+
+        let config = config::Nix {
+            substituters: vec![config::Substituter {
+                url: "file:///nonexistent/cache".to_owned(),
+                public_key: None,
+            }],
+            ..Default::default()
+        };
+        assert!(binary_cache_files(&config).is_err());
+    }
+
+    #[test]
+    fn copies_push_secret_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = dir.path().join("cache.secret");
+        std::fs::write(&key, "cache.example.org-1:secret").unwrap();
+        let push = |key: &Path| {
+            config(&format!(
+                "nix:\n  push:\n    url: file:///srv/cache?compression=zstd\n    secret_key: {}\n",
+                path_to_string(key)
+            ))
+        };
+
+        let files = binary_cache_files(&push(&key).nix).ok().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, key);
+        assert_eq!(files[0].target, Some(PathBuf::from(nix::SECRET_KEY)));
+        assert!(matches!(files[0].mode, HostFileMode::Copy));
+
+        let e = binary_cache_files(&push(&dir.path().join("missing")).nix)
+            .err()
+            .unwrap();
+        assert!(e.to_string().starts_with("Secret key"));
+    }
+
+    #[test]
+    fn enables_nix_command_for_pushing() {
+        let config = config("nix:\n  push:\n    url: s3://cache\n");
+
+        let conf = nix_conf(&config).ok().unwrap().render();
+        assert!(conf.contains("experimental-features = nix-command\n"));
+    }
+
+    #[test]
+    fn validates_pinned_references() {
+        assert!(check_release("1.8.0", "nixos-generators").is_ok());
+        assert!(check_release("v1.8.0-rc1", "nixos-generators").is_ok());
+        for version in ["", "../1.8.0", "1.8.0?x=1", "1.8.0/../main", "-1", "1..8"] {
+            assert!(
+                check_release(version, "nixos-generators").is_err(),
+                "{}",
+                version
+            );
+        }
+
+        assert!(check_commit("0123abcdef", "nixpkgs").is_ok());
+        assert!(check_commit("main", "nixpkgs").is_err());
+        assert!(check_commit("", "nixpkgs").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_release_before_downloading() {
+        let invalid = config("generators:\n  source: release\n  version: 1.8.0#x\n  sha256: abc\n");
+        let e = pinned_generators(&invalid).err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            "`1.8.0#x` is not a valid nixos-generators release"
+        );
+
+        let valid = config("generators:\n  source: release\n  version: 1.8.0\n  sha256: abc\n");
+        let download = pinned_generators(&valid).ok().unwrap().unwrap();
+        assert_eq!(
+            download.source.to_string(),
+            "https://github.com/nix-community/nixos-generators/archive/1.8.0.tar.gz"
+        );
+    }
+
+    #[test]
+    fn verifies_generators_version() {
+        assert!(verify_generators_version(Some("v1.8.0"), "1.8.0", true).is_ok());
+        assert!(verify_generators_version(Some("1.8.0"), "1.7.0", true).is_err());
+        assert!(verify_generators_version(None, nix::UNKNOWN_VERSION, false).is_ok());
+
+        // Tarballs don't record their version, but are pinned by their checksum
+        assert!(verify_generators_version(Some("1.8.0"), nix::UNKNOWN_VERSION, true).is_ok());
+        assert!(verify_generators_version(Some("1.8.0"), nix::UNKNOWN_VERSION, false).is_err());
     }
 }
@@ -0,0 +1,111 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+macro_rules! err {
+    ($($args:expr),+) => {
+        return Err(Error::new(format!($($args),+)))
+    };
+}
+
+#[derive(Default)]
+pub struct Args {
+    pub command: Command,
+    pub config: Option<PathBuf>,
+}
+
+#[derive(Default, Debug, PartialEq)]
+pub enum Command {
+    #[default]
+    Build,
+    Doctor,
+    Gc,
+}
+
+pub fn parse() -> Result<Args> {
+    parse_from(std::env::args_os().skip(1))
+}
+
+fn parse_from<I: IntoIterator<Item = OsString>>(argv: I) -> Result<Args> {
+    let mut args = Args::default();
+    let mut argv = argv.into_iter();
+    let mut command = None;
+
+    while let Some(arg) = argv.next() {
+        match arg.to_str() {
+            Some("-c") | Some("--config") => match argv.next() {
+                Some(p) => args.config = Some(p.into()),
+                None => err!("Option `{}` requires a value", arg.to_string_lossy()),
+            },
+            Some("build") if command.is_none() => command = Some(Command::Build),
+            Some("doctor") if command.is_none() => command = Some(Command::Doctor),
+            Some("gc") if command.is_none() => command = Some(Command::Gc),
+            _ => err!("Unexpected argument `{}`", arg.to_string_lossy()),
+        }
+    }
+
+    if let Some(c) = command {
+        args.command = c;
+    }
+
+    Ok(args)
+}
+
+pub struct Error {
+    error: String,
+}
+
+impl Error {
+    pub fn new<S: AsRef<str>>(error: S) -> Self {
+        let error = error.as_ref().to_owned();
+
+        Self { error }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(argv: &[&str]) -> Result<Args> {
+        parse_from(argv.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn defaults_to_build() {
+        let args = parse_args(&[]).ok().unwrap();
+
+        assert_eq!(args.command, Command::Build);
+        assert_eq!(args.config, None);
+    }
+
+    #[test]
+    fn parses_command_and_config() {
+        let args = parse_args(&["doctor", "--config", "nixops.yaml"])
+            .ok()
+            .unwrap();
+
+        assert_eq!(args.command, Command::Doctor);
+        assert_eq!(args.config, Some(PathBuf::from("nixops.yaml")));
+        assert_eq!(
+            parse_args(&["-c", "a.yaml", "gc"]).ok().unwrap().command,
+            Command::Gc
+        );
+    }
+
+    #[test]
+    fn rejects_missing_values_and_unexpected_arguments() {
+        let e = parse_args(&["-c"]).err().unwrap();
+        assert_eq!(e.to_string(), "Option `-c` requires a value");
+
+        let e = parse_args(&["build", "doctor"]).err().unwrap();
+        assert_eq!(e.to_string(), "Unexpected argument `doctor`");
+    }
+}
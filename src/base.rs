@@ -0,0 +1,205 @@
+use crate::arch::Arch;
+use crate::chroot::Chroot;
+use crate::extractor::{extract, Summary};
+use crate::fs::WorkDir;
+use crate::source::Source;
+use sha2::{Digest, Sha256, Sha512};
+use std::fs::File;
+use std::io::{copy, Result as IoResult};
+use std::path::{Path, PathBuf};
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+macro_rules! err {
+    ($($args:expr),+) => {
+        return Err(Error::new(format!($($args),+)))
+    };
+}
+
+pub const NIX_PACKAGES: [&str; 4] = ["bash", "tar", "xz", "nix"];
+
+pub trait BaseSystemProvider {
+    fn name(&self) -> &str;
+
+    fn resolve(&self, architecture: &Arch, work_dir: &WorkDir) -> Result<Artifact>;
+
+    fn verify(&self, artifact: &Artifact) -> Result<()> {
+        verify_artifact(artifact)
+    }
+
+    fn extract(&self, artifact: &Artifact, rootfs: &Path) -> Result<Summary> {
+        extract_tarball(artifact, rootfs)
+    }
+
+    fn install_packages(&self, chroot: &Chroot, packages: &[&str]) -> Result<()>;
+}
+
+pub struct Artifact {
+    pub path: PathBuf,
+    /// Where the artifact is downloaded from, if it isn't available at `path` yet.
+    pub source: Option<Source>,
+    pub size: Option<u64>,
+    pub checksum: Option<Checksum>,
+}
+
+#[derive(Clone)]
+pub enum Checksum {
+    Sha256(String),
+    Sha512(String),
+}
+
+impl Checksum {
+    pub fn value(&self) -> &str {
+        match self {
+            Self::Sha256(c) | Self::Sha512(c) => c,
+        }
+    }
+}
+
+impl std::fmt::Display for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Sha256(c) => write!(f, "SHA-256 `{}`", c),
+            Self::Sha512(c) => write!(f, "SHA-512 `{}`", c),
+        }
+    }
+}
+
+pub struct Error {
+    error: String,
+}
+
+impl Error {
+    pub fn new<S: AsRef<str>>(error: S) -> Self {
+        let error = error.as_ref().to_owned();
+
+        Self { error }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+pub fn verify_artifact(artifact: &Artifact) -> Result<()> {
+    if let Some(expected_size) = artifact.size {
+        verify_size(&artifact.path, expected_size)?;
+    }
+
+    if let Some(checksum) = &artifact.checksum {
+        verify_checksum(&artifact.path, checksum)?;
+    }
+
+    Ok({})
+}
+
+pub fn extract_tarball(artifact: &Artifact, rootfs: &Path) -> Result<Summary> {
+    match extract(&artifact.path, rootfs) {
+        Ok(s) => Ok(s),
+        Err(e) => err!("Unable to extract `{}`: {}", artifact.path.display(), e),
+    }
+}
+
+fn verify_size(p: &Path, expected_size: u64) -> Result<()> {
+    let actual_size = match std::fs::metadata(p) {
+        Ok(m) => m.len(),
+        Err(e) => err!("Unable to access `{}`: {}", p.display(), e),
+    };
+
+    if actual_size == expected_size {
+        return Ok({});
+    }
+
+    err!(
+        "Size of `{}` doesn't match. Expected {}, but got {}",
+        p.display(),
+        expected_size,
+        actual_size
+    )
+}
+
+fn verify_checksum(p: &Path, c: &Checksum) -> Result<()> {
+    let actual_checksum = match checksum_file(p, c) {
+        Ok(c) => c,
+        Err(e) => err!("Unable to read `{}`: {}", p.display(), e),
+    };
+
+    if actual_checksum.eq_ignore_ascii_case(c.value()) {
+        return Ok({});
+    }
+
+    err!(
+        "Checksum of `{}` doesn't match. Expected {}, got `{}`",
+        p.display(),
+        c,
+        actual_checksum
+    )
+}
+
+fn checksum_file(p: &Path, c: &Checksum) -> IoResult<String> {
+    let mut file = File::open(p)?;
+
+    Ok(match c {
+        Checksum::Sha256(_) => {
+            let mut hasher = Sha256::new();
+            copy(&mut file, &mut hasher)?;
+            format!("{:x}", hasher.finalize())
+        }
+        Checksum::Sha512(_) => {
+            let mut hasher = Sha512::new();
+            copy(&mut file, &mut hasher)?;
+            format!("{:x}", hasher.finalize())
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO_SHA256: &str = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+
+    fn artifact(
+        dir: &tempfile::TempDir,
+        size: Option<u64>,
+        checksum: Option<Checksum>,
+    ) -> Artifact {
+        let path = dir.path().join("base.tar.gz");
+        std::fs::write(&path, "hello\n").unwrap();
+
+        Artifact {
+            path,
+            source: None,
+            size,
+            checksum,
+        }
+    }
+
+    #[test]
+    fn verifies_size_and_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let checksum = Checksum::Sha256(HELLO_SHA256.to_uppercase());
+
+        assert!(verify_artifact(&artifact(&dir, Some(6), Some(checksum))).is_ok());
+        assert!(verify_artifact(&artifact(&dir, None, None)).is_ok());
+    }
+
+    #[test]
+    fn rejects_size_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let e = verify_artifact(&artifact(&dir, Some(7), None)).unwrap_err();
+        assert!(e.to_string().contains("Expected 7, but got 6"));
+    }
+
+    #[test]
+    fn rejects_checksum_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let checksum = Checksum::Sha512(HELLO_SHA256.to_owned());
+
+        let e = verify_artifact(&artifact(&dir, None, Some(checksum))).unwrap_err();
+        assert!(e.to_string().contains("Checksum of"));
+    }
+}
@@ -1,40 +1,76 @@
 use std::ffi::{OsStr, OsString};
-use std::io::{Error, ErrorKind, Result};
-use std::path::Path;
+use std::io::{Error, Result};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
-pub fn execute<P: AsRef<Path>, A: AsRef<OsStr>, I: IntoIterator<Item = A>>(
-    chroot: P,
+pub struct Chroot {
+    root: PathBuf,
+    env: Vec<(OsString, OsString)>,
+}
+
+impl Chroot {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_owned(),
+            env: vec![],
+        }
+    }
+
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(mut self, key: K, value: V) -> Self {
+        self.env
+            .push((key.as_ref().to_owned(), value.as_ref().to_owned()));
+
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+}
+
+pub fn execute<A: AsRef<OsStr>, I: IntoIterator<Item = A>>(chroot: &Chroot, args: I) -> Result<()> {
+    run(chroot, args).map(|_| {})
+}
+
+pub fn output<A: AsRef<OsStr>, I: IntoIterator<Item = A>>(
+    chroot: &Chroot,
     args: I,
-) -> Result<()> {
+) -> Result<String> {
+    run(chroot, args)
+}
+
+fn run<A: AsRef<OsStr>, I: IntoIterator<Item = A>>(chroot: &Chroot, args: I) -> Result<String> {
     let mut args: Vec<OsString> = args.into_iter().map(|a| a.as_ref().to_owned()).collect();
     let mut args_vec: Vec<OsString> = Vec::with_capacity(args.len() + 3);
-    args_vec.push(chroot.as_ref().as_os_str().to_owned());
+    args_vec.push(chroot.root.as_os_str().to_owned());
     args_vec.push("/usr/bin/env".into());
     args_vec.push("TMPDIR=/tmp".into());
     args_vec.append(&mut args);
 
-    let result = match Command::new("chroot").args(&args_vec).output() {
+    // Variables are passed through the environment rather than arguments of `env`, so that
+    // credentials they may contain (e.g. proxy URLs) are not echoed below
+    let result = match Command::new("chroot")
+        .args(&args_vec)
+        .envs(chroot.env.iter().map(|(k, v)| (k, v)))
+        .output()
+    {
         Ok(o) => o,
         Err(e) => return Err(Error::new(e.kind(), format!("{}", e))),
     };
 
     if !result.status.success() {
-        return Err(Error::new(
-            ErrorKind::Other,
-            format!(
-                "= stdout:\n{}\n= stderr:\n{}",
-                String::from_utf8_lossy(&result.stdout),
-                String::from_utf8_lossy(&result.stderr)
-            ),
-        ));
+        return Err(Error::other(format!(
+            "= stdout:\n{}\n= stderr:\n{}",
+            String::from_utf8_lossy(&result.stdout),
+            String::from_utf8_lossy(&result.stderr)
+        )));
     }
 
     eprint!(" chroot");
     for i in args_vec {
         eprint!(" {}", i.to_string_lossy());
     }
-    eprintln!("");
+    eprintln!();
 
     eprintln!(
         "= stdout:\n{}\n= stderr:\n{}",
@@ -42,5 +78,5 @@ pub fn execute<P: AsRef<Path>, A: AsRef<OsStr>, I: IntoIterator<Item = A>>(
         String::from_utf8_lossy(&result.stderr)
     );
 
-    Ok({})
+    Ok(String::from_utf8_lossy(&result.stdout).into_owned())
 }
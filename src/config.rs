@@ -0,0 +1,569 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+macro_rules! err {
+    ($($args:expr),+) => {
+        return Err(Error::new(format!($($args),+)))
+    };
+}
+
+const DEFAULT_CONFIG_PATH: &str = "./nixops.yaml";
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub work_dir: PathBuf,
+    pub base: Base,
+    pub preflight: Preflight,
+    pub dns: Dns,
+    pub host_files: Vec<HostFile>,
+    pub environment: BTreeMap<String, String>,
+    pub http: Http,
+    pub downloads: Downloads,
+    pub nixpkgs: Nixpkgs,
+    pub flake: Option<Flake>,
+    pub generators: Generators,
+    pub container: Container,
+    pub nix: Nix,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            work_dir: PathBuf::from("./workdir/"),
+            base: Base::default(),
+            preflight: Preflight::default(),
+            dns: Dns::default(),
+            host_files: vec![],
+            environment: BTreeMap::new(),
+            http: Http::default(),
+            downloads: Downloads::default(),
+            nixpkgs: Nixpkgs::default(),
+            flake: None,
+            generators: Generators::default(),
+            container: Container::default(),
+            nix: Nix::default(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "provider", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Base {
+    Alpine {
+        #[serde(default = "default_alpine_mirror")]
+        mirror: String,
+        /// Required for plain HTTP mirrors.
+        #[serde(default)]
+        sha512: Option<String>,
+    },
+    Local {
+        path: PathBuf,
+        sha256: Option<String>,
+        sha512: Option<String>,
+    },
+}
+
+impl Default for Base {
+    fn default() -> Self {
+        Self::Alpine {
+            mirror: default_alpine_mirror(),
+            sha512: None,
+        }
+    }
+}
+
+fn default_alpine_mirror() -> String {
+    crate::alpine::DEFAULT_MIRROR.to_owned()
+}
+
+/// Zero disables a check.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Preflight {
+    pub min_free_space: Size,
+    pub min_free_inodes: u64,
+}
+
+impl Default for Preflight {
+    fn default() -> Self {
+        Self {
+            min_free_space: Size(8 << 30),
+            min_free_inodes: 200_000,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Dns {
+    pub mode: DnsMode,
+    /// Only used in `static` mode.
+    pub nameservers: Vec<String>,
+    pub search: Vec<String>,
+    pub required: bool,
+}
+
+impl Default for Dns {
+    fn default() -> Self {
+        Self {
+            mode: DnsMode::default(),
+            nameservers: vec![],
+            search: vec![],
+            required: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum DnsMode {
+    #[default]
+    Copy,
+    Bind,
+    Static,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostFile {
+    pub path: PathBuf,
+    /// Inside the chroot environment, the same as `path` by default.
+    #[serde(default)]
+    pub target: Option<PathBuf>,
+    #[serde(default)]
+    pub mode: HostFileMode,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostFileMode {
+    #[default]
+    Copy,
+    Bind,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http {
+    pub ca_certificates: Vec<PathBuf>,
+    pub proxy: Option<String>,
+    pub no_proxy: Vec<String>,
+    pub env_proxy: bool,
+    pub allow_http: bool,
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Self {
+            ca_certificates: vec![],
+            proxy: None,
+            no_proxy: vec![],
+            env_proxy: true,
+            allow_http: false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Downloads {
+    pub concurrency: usize,
+    /// Per second, shared by all downloads, zero for no limit.
+    pub max_bandwidth: Size,
+    /// Also limits Nix in the chroot environment, but not `apk`, which offers no limit.
+    pub limit_chroot: bool,
+    pub extra: Vec<ExtraDownload>,
+}
+
+impl Downloads {
+    pub fn chroot_limit(&self) -> Option<Size> {
+        Some(self.max_bandwidth).filter(|l| self.limit_chroot && l.0 > 0)
+    }
+}
+
+impl Default for Downloads {
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            max_bandwidth: Size(0),
+            limit_chroot: false,
+            extra: vec![],
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExtraDownload {
+    /// `https://`, `http://` or `file://` URL, plain HTTP requires a checksum.
+    pub url: String,
+    /// In the `downloads` directory, the last segment of the URL by default.
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub sha256: Option<String>,
+    #[serde(default)]
+    pub sha512: Option<String>,
+}
+
+/// `sha256` is the SHA-256 of the tarball itself, as printed by `sha256sum`.
+#[derive(Deserialize)]
+#[serde(tag = "source", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Nixpkgs {
+    Channel {
+        #[serde(default = "default_channel")]
+        name: String,
+    },
+    Tarball { url: String, sha256: String },
+    Commit { commit: String, sha256: String },
+}
+
+impl Default for Nixpkgs {
+    fn default() -> Self {
+        Self::Channel {
+            name: default_channel(),
+        }
+    }
+}
+
+fn default_channel() -> String {
+    "nixpkgs-unstable".to_owned()
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "source", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Generators {
+    /// `sha256` is the SHA-256 of the tarball itself, as printed by `sha256sum`.
+    Nixpkgs {
+        #[serde(default)]
+        version: Option<String>,
+    },
+    Release { version: String, sha256: String },
+    Commit {
+        commit: String,
+        sha256: String,
+        #[serde(default)]
+        version: Option<String>,
+    },
+    Flake { commit: String },
+}
+
+impl Default for Generators {
+    fn default() -> Self {
+        Self::Nixpkgs { version: None }
+    }
+}
+
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Container {
+    pub hostname: Option<String>,
+    /// Time zone from the tz database, e.g. `Europe/Berlin`.
+    pub timezone: Option<String>,
+    /// Default locale, e.g. `en_US.UTF-8`.
+    pub locale: Option<String>,
+    pub users: Vec<User>,
+    /// Services enabled through `services.<name>.enable`, e.g. `openssh`.
+    pub services: Vec<String>,
+    /// Attributes of nixpkgs, e.g. `htop` or `python3Packages.requests`.
+    pub packages: Vec<String>,
+    pub networking: Networking,
+    /// Files, or directories with a `default.nix` for modules importing their siblings.
+    pub imports: Vec<PathBuf>,
+}
+
+#[derive(Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct User {
+    /// In the `downloads` directory, the last segment of the URL by default.
+    pub name: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub ssh_keys: Vec<String>,
+}
+
+#[derive(Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Networking {
+    pub interface: String,
+    /// With prefix length, e.g. `192.168.1.10/24`. DHCP is used without one.
+    pub address: Option<String>,
+    pub gateway: Option<String>,
+    pub nameservers: Vec<String>,
+}
+
+impl Default for Networking {
+    fn default() -> Self {
+        Self {
+            interface: "eth0".to_owned(),
+            address: None,
+            gateway: None,
+            nameservers: vec![],
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Flake {
+    pub path: PathBuf,
+    /// E.g. `nixosConfigurations.lxc.config.system.build.tarball`.
+    pub output: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Nix {
+    pub settings: BTreeMap<String, NixSetting>,
+    pub substituters: Vec<Substituter>,
+    pub netrc: Option<PathBuf>,
+    /// Mounted as `/nix` of the chroot environment and reused by subsequent builds.
+    pub store: Option<PathBuf>,
+    pub gc: Gc,
+    pub push: Option<Push>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Push {
+    /// `https://`, `http://` or `file://` URL, plain HTTP requires a checksum.
+    pub url: String,
+    /// As generated by `nix-store --generate-binary-cache-key`.
+    #[serde(default)]
+    pub secret_key: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Gc {
+    /// Profile generations older than this are deleted.
+    pub max_age_days: Option<u32>,
+    /// Unreachable store paths are deleted until the store is no larger than this.
+    pub max_size: Option<Size>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Substituter {
+    /// `https://`, `http://` or `file://` URL, plain HTTP requires a checksum.
+    pub url: String,
+    #[serde(default)]
+    pub public_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum NixSetting {
+    Bool(bool),
+    Integer(u64),
+    String(String),
+    List(Vec<String>),
+}
+
+/// Bytes, as a plain number or with a `K`, `M`, `G` or `T` suffix.
+#[derive(Clone, Copy)]
+pub struct Size(pub u64);
+
+impl Size {
+    fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        let (number, shift) = match s.char_indices().last()? {
+            (i, 'K') | (i, 'k') => (&s[..i], 10),
+            (i, 'M') | (i, 'm') => (&s[..i], 20),
+            (i, 'G') | (i, 'g') => (&s[..i], 30),
+            (i, 'T') | (i, 't') => (&s[..i], 40),
+            _ => (s, 0),
+        };
+
+        let number: u64 = number.trim().parse().ok()?;
+        number.checked_mul(1 << shift).map(Self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Size {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> core::result::Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Value {
+            Bytes(u64),
+            Text(String),
+        }
+
+        match Value::deserialize(deserializer)? {
+            Value::Bytes(b) => Ok(Self(b)),
+            Value::Text(t) => match Self::parse(&t) {
+                Some(s) => Ok(s),
+                None => Err(serde::de::Error::custom(format!(
+                    "invalid size `{}`, expected a number of bytes with an optional `K`, `M`, `G` or `T` suffix",
+                    t
+                ))),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Size {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+        let mut value = self.0 as f64;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+
+        match unit {
+            0 => write!(f, "{} B", self.0),
+            _ => write!(f, "{:.1} {}", value, UNITS[unit]),
+        }
+    }
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: Option<P>) -> Result<Self> {
+        match path {
+            Some(p) => load_file(p.as_ref()),
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                load_file(Path::new(DEFAULT_CONFIG_PATH))
+            }
+            None => Ok(Self::default()),
+        }
+    }
+}
+
+pub struct Error {
+    error: String,
+}
+
+impl Error {
+    pub fn new<S: AsRef<str>>(error: S) -> Self {
+        let error = error.as_ref().to_owned();
+
+        Self { error }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+fn load_file(p: &Path) -> Result<Config> {
+    let contents = match std::fs::read_to_string(p) {
+        Ok(c) => c,
+        Err(e) => err!("Unable to read configuration file `{}`: {}", p.display(), e),
+    };
+
+    match serde_yaml::from_str(&contents) {
+        Ok(c) => Ok(c),
+        Err(e) => err!(
+            "Unable to parse configuration file `{}`: {}",
+            p.display(),
+            e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(yaml: &str) -> core::result::Result<Config, serde_yaml::Error> {
+        serde_yaml::from_str(yaml)
+    }
+
+    #[test]
+    fn defaults_to_alpine() {
+        let config = parse("work_dir: /tmp/work").unwrap();
+
+        assert_eq!(config.work_dir, PathBuf::from("/tmp/work"));
+        match config.base {
+            Base::Alpine { mirror, sha512 } => {
+                assert_eq!(mirror, crate::alpine::DEFAULT_MIRROR);
+                assert_eq!(sha512, None);
+            }
+            Base::Local { .. } => panic!("expected the Alpine base system"),
+        }
+    }
+
+    #[test]
+    fn parses_local_base() {
+        let config = parse("base:\n  provider: local\n  path: /srv/rootfs.tar.gz\n").unwrap();
+
+        match config.base {
+            Base::Local { path, .. } => assert_eq!(path, PathBuf::from("/srv/rootfs.tar.gz")),
+            Base::Alpine { .. } => panic!("expected a local base system"),
+        }
+        assert!(parse("base:\n  provider: debian\n").is_err());
+    }
+
+    #[test]
+    fn loads_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nixops.yaml");
+        std::fs::write(&path, "work_dir: /tmp/work\n").unwrap();
+
+        let config = Config::load(Some(&path)).ok().unwrap();
+        assert_eq!(config.work_dir, PathBuf::from("/tmp/work"));
+
+        std::fs::write(&path, "unknown: true\n").unwrap();
+        let e = Config::load(Some(&path)).err().unwrap();
+        assert!(e
+            .to_string()
+            .starts_with("Unable to parse configuration file"));
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(Size::parse("512").map(|s| s.0), Some(512));
+        assert_eq!(Size::parse(" 8G ").map(|s| s.0), Some(8 << 30));
+        assert_eq!(Size::parse("10 m").map(|s| s.0), Some(10 << 20));
+        assert_eq!(Size::parse("2T").map(|s| s.0), Some(2 << 40));
+        assert!(Size::parse("").is_none());
+        assert!(Size::parse("G").is_none());
+        assert!(Size::parse("1.5G").is_none());
+        assert!(Size::parse("18446744073709551615K").is_none());
+    }
+
+    #[test]
+    fn deserializes_sizes() {
+        let config = parse("preflight:\n  min_free_space: 4G\n").unwrap();
+        assert_eq!(config.preflight.min_free_space.0, 4 << 30);
+
+        let config = parse("preflight:\n  min_free_space: 1024\n").unwrap();
+        assert_eq!(config.preflight.min_free_space.0, 1024);
+
+        let e = parse("preflight:\n  min_free_space: lots\n").err().unwrap();
+        assert!(e.to_string().contains("invalid size `lots`"));
+    }
+
+    #[test]
+    fn displays_sizes() {
+        assert_eq!(Size(512).to_string(), "512 B");
+        assert_eq!(Size(1536).to_string(), "1.5 KiB");
+        assert_eq!(Size(8 << 30).to_string(), "8.0 GiB");
+        assert_eq!(Size(3 << 50).to_string(), "3072.0 TiB");
+    }
+
+    #[test]
+    fn limits_chroot_downloads_only_when_enabled() {
+        let config = parse("downloads:\n  max_bandwidth: 2M\n").unwrap();
+        assert_eq!(config.downloads.max_bandwidth.0, 2 << 20);
+        assert!(config.downloads.chroot_limit().is_none());
+
+        let config = parse("downloads:\n  max_bandwidth: 2M\n  limit_chroot: true\n").unwrap();
+        assert_eq!(config.downloads.chroot_limit().map(|l| l.0), Some(2 << 20));
+
+        let config = parse("downloads:\n  limit_chroot: true\n").unwrap();
+        assert!(config.downloads.chroot_limit().is_none());
+    }
+}
@@ -0,0 +1,331 @@
+use crate::config::{Dns, DnsMode};
+use crate::fs::path_to_string;
+use crate::mount::bind_mount;
+use std::fs::{OpenOptions, Permissions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use sys_mount::{Mount, UnmountDrop};
+
+type Result<T> = core::result::Result<T, Error>;
+
+macro_rules! err {
+    ($($args:expr),+) => {
+        return Err(Error::new(format!($($args),+)))
+    };
+}
+
+const HOST_RESOLV_CONF: &str = "/etc/resolv.conf";
+
+const RESOLVED_RESOLV_CONF: &str = "/run/systemd/resolve/resolv.conf";
+const RESOLVED_STUB_ADDRESS: &str = "127.0.0.53";
+
+pub struct ResolvConf {
+    path: PathBuf,
+    original: Original,
+    mount: Option<UnmountDrop<Mount>>,
+    description: String,
+    restored: bool,
+}
+
+enum Original {
+    Missing,
+    File(Vec<u8>, Permissions),
+    Symlink(PathBuf),
+}
+
+impl ResolvConf {
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    pub fn restore(mut self) -> Result<()> {
+        // Unmounts the bind-mounted host file, if any
+        self.mount.take();
+        self.restored = true;
+
+        restore_original(&self.path, &self.original)
+    }
+}
+
+impl Drop for ResolvConf {
+    fn drop(&mut self) {
+        if self.restored {
+            return;
+        }
+
+        self.mount.take();
+        let _ = restore_original(&self.path, &self.original);
+    }
+}
+
+pub fn configure<P: AsRef<Path>>(rootfs: P, config: &Dns) -> Result<ResolvConf> {
+    validate(config)?;
+
+    let etc = rootfs.as_ref().join("etc");
+    match etc.symlink_metadata() {
+        Ok(m) if m.is_dir() => {}
+        Ok(_) => err!("`{}` is not a directory", path_to_string(&etc)),
+        Err(e) => err!("Unable to access `{}`: {}", path_to_string(&etc), e),
+    }
+
+    let path = etc.join("resolv.conf");
+    let original = backup(&path)?;
+
+    // The existing entry may be a symlink, which must not be followed to the host
+    if let Err(e) = remove(&path) {
+        err!("Unable to remove `{}`: {}", path_to_string(&path), e);
+    }
+
+    let (mount, description) = match apply(&path, config) {
+        Ok(r) => r,
+        Err(e) => {
+            let _ = restore_original(&path, &original);
+
+            return Err(e);
+        }
+    };
+
+    Ok(ResolvConf {
+        path,
+        original,
+        mount,
+        description,
+        restored: false,
+    })
+}
+
+pub struct Error {
+    error: String,
+}
+
+impl Error {
+    pub fn new<S: AsRef<str>>(error: S) -> Self {
+        let error = error.as_ref().to_owned();
+
+        Self { error }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+fn validate(config: &Dns) -> Result<()> {
+    match config.mode {
+        DnsMode::Static if config.nameservers.is_empty() => {
+            err!("At least one name server has to be configured in `static` DNS mode")
+        }
+        DnsMode::Copy | DnsMode::Bind
+            if !config.nameservers.is_empty() || !config.search.is_empty() =>
+        {
+            err!("Name servers and search domains can only be configured in `static` DNS mode")
+        }
+        _ => Ok({}),
+    }
+}
+
+fn apply(path: &Path, config: &Dns) -> Result<(Option<UnmountDrop<Mount>>, String)> {
+    match config.mode {
+        DnsMode::Copy => {
+            let source = host_resolv_conf();
+            let contents = match std::fs::read(&source) {
+                Ok(c) => c,
+                Err(e) => err!("Unable to read `{}`: {}", path_to_string(&source), e),
+            };
+            write_new(path, &contents)?;
+
+            Ok((None, format!("copied `{}`", path_to_string(&source))))
+        }
+        DnsMode::Bind => {
+            let source = host_resolv_conf();
+            write_new(path, b"")?;
+
+            match bind_mount(&source, path, true) {
+                Ok(m) => Ok((
+                    Some(m),
+                    format!("bind-mounted `{}` read-only", path_to_string(&source)),
+                )),
+                Err(e) => err!(
+                    "Unable to bind-mount `{}` onto `{}`: {}",
+                    path_to_string(&source),
+                    path_to_string(path),
+                    e
+                ),
+            }
+        }
+        DnsMode::Static => {
+            let mut contents = String::new();
+            for nameserver in &config.nameservers {
+                contents.push_str(&format!("nameserver {}\n", nameserver));
+            }
+            if !config.search.is_empty() {
+                contents.push_str(&format!("search {}\n", config.search.join(" ")));
+            }
+            write_new(path, contents.as_bytes())?;
+
+            Ok((
+                None,
+                format!("configured name servers {}", config.nameservers.join(", ")),
+            ))
+        }
+    }
+}
+
+/// Looks through systemd-resolved's stub listener to the real upstream servers.
+fn host_resolv_conf() -> PathBuf {
+    let host = PathBuf::from(HOST_RESOLV_CONF);
+
+    let stub_link = match std::fs::read_link(&host) {
+        Ok(target) => target.ends_with("stub-resolv.conf"),
+        Err(_) => false,
+    };
+    let stub_address = match std::fs::read_to_string(&host) {
+        Ok(c) => c.lines().any(|l| {
+            let mut fields = l.split_whitespace();
+            fields.next() == Some("nameserver") && fields.next() == Some(RESOLVED_STUB_ADDRESS)
+        }),
+        Err(_) => false,
+    };
+
+    if (stub_link || stub_address) && Path::new(RESOLVED_RESOLV_CONF).exists() {
+        return PathBuf::from(RESOLVED_RESOLV_CONF);
+    }
+
+    host
+}
+
+fn backup(path: &Path) -> Result<Original> {
+    let metadata = match path.symlink_metadata() {
+        Ok(m) => m,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Original::Missing),
+        Err(e) => err!("Unable to access `{}`: {}", path_to_string(path), e),
+    };
+
+    if metadata.file_type().is_symlink() {
+        return match std::fs::read_link(path) {
+            Ok(t) => Ok(Original::Symlink(t)),
+            Err(e) => err!("Unable to read `{}`: {}", path_to_string(path), e),
+        };
+    }
+
+    if !metadata.is_file() {
+        err!("`{}` is not a regular file", path_to_string(path));
+    }
+
+    match std::fs::read(path) {
+        Ok(c) => Ok(Original::File(c, metadata.permissions())),
+        Err(e) => err!("Unable to read `{}`: {}", path_to_string(path), e),
+    }
+}
+
+fn restore_original(path: &Path, original: &Original) -> Result<()> {
+    if let Err(e) = remove(path) {
+        err!("Unable to remove `{}`: {}", path_to_string(path), e);
+    }
+
+    match original {
+        Original::Missing => Ok({}),
+        Original::File(contents, permissions) => {
+            write_new(path, contents)?;
+
+            match std::fs::set_permissions(path, permissions.clone()) {
+                Ok(_) => Ok({}),
+                Err(e) => err!(
+                    "Unable to set permissions of `{}`: {}",
+                    path_to_string(path),
+                    e
+                ),
+            }
+        }
+        Original::Symlink(target) => match std::os::unix::fs::symlink(target, path) {
+            Ok(_) => Ok({}),
+            Err(e) => err!("Unable to create `{}`: {}", path_to_string(path), e),
+        },
+    }
+}
+
+fn remove(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok({}),
+    }
+}
+
+fn write_new(path: &Path, contents: &[u8]) -> Result<()> {
+    let result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .and_then(|mut f| f.write_all(contents));
+
+    match result {
+        Ok(_) => Ok({}),
+        Err(e) => err!("Unable to create `{}`: {}", path_to_string(path), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rootfs() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("etc")).unwrap();
+        std::os::unix::fs::symlink("/run/resolv.conf", dir.path().join("etc/resolv.conf")).unwrap();
+
+        dir
+    }
+
+    fn static_dns() -> Dns {
+        Dns {
+            mode: DnsMode::Static,
+            nameservers: vec!["192.0.2.53".to_owned()],
+            search: vec!["example.org".to_owned()],
+            ..Dns::default()
+        }
+    }
+
+    #[test]
+    fn writes_static_configuration() {
+        let dir = rootfs();
+        let path = dir.path().join("etc/resolv.conf");
+
+        let resolv_conf = configure(dir.path(), &static_dns()).ok().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "nameserver 192.0.2.53\nsearch example.org\n"
+        );
+
+        assert!(resolv_conf.restore().is_ok());
+        assert_eq!(
+            std::fs::read_link(&path).unwrap(),
+            PathBuf::from("/run/resolv.conf")
+        );
+    }
+
+    #[test]
+    fn restores_original_when_dropped() {
+        let dir = rootfs();
+        let path = dir.path().join("etc/resolv.conf");
+
+        drop(configure(dir.path(), &static_dns()).ok().unwrap());
+        assert_eq!(
+            std::fs::read_link(&path).unwrap(),
+            PathBuf::from("/run/resolv.conf")
+        );
+    }
+
+    #[test]
+    fn rejects_name_servers_outside_static_mode() {
+        let dir = rootfs();
+        let config = Dns {
+            mode: DnsMode::Copy,
+            ..static_dns()
+        };
+
+        assert!(configure(dir.path(), &config).is_err());
+        assert!(std::fs::read_link(dir.path().join("etc/resolv.conf")).is_ok());
+    }
+}
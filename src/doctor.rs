@@ -0,0 +1,307 @@
+use crate::arch::Arch;
+use crate::config::Config;
+use crate::fs::path_to_string;
+use crate::preflight::check_work_dir;
+use std::path::Path;
+
+/// Required for `mount(2)`.
+const CAP_SYS_ADMIN: u32 = 21;
+
+type CheckFn = fn(&Config) -> Vec<Check>;
+
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+struct Check {
+    status: Status,
+    detail: String,
+    hint: Option<&'static str>,
+}
+
+impl Check {
+    fn pass<S: Into<String>>(detail: S) -> Self {
+        Self {
+            status: Status::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn warn<S: Into<String>>(detail: S, hint: &'static str) -> Self {
+        Self {
+            status: Status::Warn,
+            detail: detail.into(),
+            hint: Some(hint),
+        }
+    }
+
+    fn fail<S: Into<String>>(detail: S, hint: &'static str) -> Self {
+        Self {
+            status: Status::Fail,
+            detail: detail.into(),
+            hint: Some(hint),
+        }
+    }
+}
+
+pub fn run(config: &Config) -> bool {
+    let checks: [(&str, CheckFn); 8] = [
+        ("platform and architecture", |_| vec![check_platform()]),
+        ("privileges", |_| vec![check_privileges()]),
+        ("mount permission", |_| vec![check_mount_permission()]),
+        ("devtmpfs and devpts support", |_| check_filesystems()),
+        ("`chroot` command", |_| vec![check_chroot()]),
+        ("binfmt_misc handlers", |_| vec![check_binfmt_misc()]),
+        ("cgroup version", |_| vec![check_cgroups()]),
+        ("disk space", check_disk_space),
+    ];
+
+    let mut passed = true;
+    for (name, check) in checks {
+        println!("Checking {}...", name);
+
+        for c in check(config) {
+            match c.status {
+                Status::Pass => println!("... OK: {}", c.detail),
+                Status::Warn => println!("... WARNING: {}", c.detail),
+                Status::Fail => {
+                    passed = false;
+                    println!("... ERROR: {}", c.detail)
+                }
+            }
+
+            if let Some(hint) = c.hint {
+                println!("    hint: {}", hint);
+            }
+        }
+    }
+
+    passed
+}
+
+fn check_platform() -> Check {
+    if std::env::consts::OS != "linux" {
+        return Check::fail(
+            format!("`{}` is not supported", std::env::consts::OS),
+            "the build pipeline only runs on Linux",
+        );
+    }
+
+    match Arch::new(std::env::consts::ARCH) {
+        Ok(_) => Check::pass(format!("linux on {}", std::env::consts::ARCH)),
+        Err(_) => Check::fail(
+            format!("`{}` architecture is not supported", std::env::consts::ARCH),
+            "only `x86`, `x86_64` and `aarch64` hosts are supported",
+        ),
+    }
+}
+
+fn check_privileges() -> Check {
+    // SAFETY: geteuid() has no preconditions and cannot fail.
+    if unsafe { libc::geteuid() } == 0 {
+        return Check::pass("running as root");
+    }
+
+    let max_namespaces = read_trimmed("/proc/sys/user/max_user_namespaces")
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0);
+    // Debian and Ubuntu kernels can additionally restrict user namespaces to root
+    let unprivileged_clone = read_trimmed("/proc/sys/kernel/unprivileged_userns_clone")
+        .map(|v| v != "0")
+        .unwrap_or(true);
+
+    if max_namespaces > 0 && unprivileged_clone {
+        return Check::warn(
+            "not running as root, but unprivileged user namespaces are available",
+            "run the build as root, or inside a user and mount namespace, e.g. `unshare -r -m`",
+        );
+    }
+
+    Check::fail(
+        "not running as root and unprivileged user namespaces are unavailable",
+        "run the build as root, e.g. using `sudo`",
+    )
+}
+
+fn check_mount_permission() -> Check {
+    let status = match std::fs::read_to_string("/proc/self/status") {
+        Ok(s) => s,
+        Err(e) => {
+            return Check::fail(
+                format!("unable to read `/proc/self/status`: {}", e),
+                "make sure `/proc` is mounted",
+            )
+        }
+    };
+
+    let capabilities = status
+        .lines()
+        .find_map(|l| l.strip_prefix("CapEff:"))
+        .and_then(|c| u64::from_str_radix(c.trim(), 16).ok());
+
+    match capabilities {
+        Some(c) if c & (1 << CAP_SYS_ADMIN) != 0 => {
+            Check::pass("`CAP_SYS_ADMIN` capability is effective")
+        }
+        Some(_) => Check::fail(
+            "`CAP_SYS_ADMIN` capability is missing, file systems can't be mounted",
+            "run as root outside of a restricted container, or grant the container `CAP_SYS_ADMIN`",
+        ),
+        None => Check::fail(
+            "unable to determine effective capabilities",
+            "make sure `/proc` is mounted",
+        ),
+    }
+}
+
+fn check_filesystems() -> Vec<Check> {
+    let filesystems = match std::fs::read_to_string("/proc/filesystems") {
+        Ok(f) => f,
+        Err(e) => {
+            return vec![Check::fail(
+                format!("unable to read `/proc/filesystems`: {}", e),
+                "make sure `/proc` is mounted",
+            )]
+        }
+    };
+
+    ["devtmpfs", "devpts", "proc", "sysfs"]
+        .iter()
+        .map(|fs| {
+            let supported = filesystems
+                .lines()
+                .any(|l| l.split_whitespace().last() == Some(fs));
+
+            match supported {
+                true => Check::pass(format!("`{}` is supported by the kernel", fs)),
+                false => Check::fail(
+                    format!("`{}` is not supported by the kernel", fs),
+                    "enable the file system in the kernel configuration",
+                ),
+            }
+        })
+        .collect()
+}
+
+fn check_chroot() -> Check {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+
+    match std::env::split_paths(&path)
+        .map(|p| p.join("chroot"))
+        .find(|p| p.is_file())
+    {
+        Some(p) => Check::pass(format!("found `{}`", path_to_string(p))),
+        None => Check::fail(
+            "`chroot` was not found in `PATH`",
+            "install coreutils, or add the directory containing `chroot` to `PATH`",
+        ),
+    }
+}
+
+fn check_binfmt_misc() -> Check {
+    let binfmt_misc = Path::new("/proc/sys/fs/binfmt_misc");
+    if !binfmt_misc.join("status").exists() {
+        return Check::warn(
+            "binfmt_misc is not mounted, only native architecture binaries can run",
+            "mount it with `mount -t binfmt_misc binfmt_misc /proc/sys/fs/binfmt_misc`",
+        );
+    }
+
+    let mut handlers: Vec<String> = match binfmt_misc.read_dir() {
+        Ok(d) => d
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .filter(|n| n != "status" && n != "register")
+            .filter(|n| {
+                read_trimmed(binfmt_misc.join(n))
+                    .map(|c| c.starts_with("enabled"))
+                    .unwrap_or(false)
+            })
+            .collect(),
+        Err(e) => {
+            return Check::warn(
+                format!("unable to list binfmt_misc handlers: {}", e),
+                "check permissions of `/proc/sys/fs/binfmt_misc`",
+            )
+        }
+    };
+    handlers.sort();
+
+    match handlers.is_empty() {
+        true => Check::warn(
+            "no binfmt_misc handlers are registered, only native architecture binaries can run",
+            "install `qemu-user-static` (or `binfmt-support`) to run foreign architectures",
+        ),
+        false => Check::pass(format!("enabled handlers: {}", handlers.join(", "))),
+    }
+}
+
+fn check_cgroups() -> Check {
+    if Path::new("/sys/fs/cgroup/cgroup.controllers").exists() {
+        return Check::pass("cgroup v2 (unified hierarchy)");
+    }
+
+    if Path::new("/sys/fs/cgroup").is_dir() {
+        return Check::pass("cgroup v1 (legacy hierarchy)");
+    }
+
+    Check::warn(
+        "cgroups are not mounted at `/sys/fs/cgroup`",
+        "mount cgroup2 at `/sys/fs/cgroup` if builds inside the chroot need it",
+    )
+}
+
+fn check_disk_space(config: &Config) -> Vec<Check> {
+    // The work dir may not exist yet, in which case it will be created on its parent file system
+    let work_dir = match config.work_dir.ancestors().find(|p| p.exists()) {
+        Some(p) if p.as_os_str().is_empty() => Path::new("."),
+        Some(p) => p,
+        None => Path::new("."),
+    };
+
+    check_work_dir(work_dir, &config.preflight)
+        .into_iter()
+        .map(|f| match f.passed {
+            true => Check::pass(f.message),
+            false => Check::fail(
+                f.message,
+                "free up space, remount the file system, or point `work_dir` to another one",
+            ),
+        })
+        .collect()
+}
+
+fn read_trimmed<P: AsRef<Path>>(path: P) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|c| c.trim().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_disk_space_of_the_nearest_existing_ancestor() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config: Config = serde_yaml::from_str("preflight:\n  min_free_space: 0\n").unwrap();
+        config.work_dir = dir.path().join("not/created/yet");
+
+        let checks = check_disk_space(&config);
+        assert_eq!(checks.len(), 4);
+        assert!(matches!(checks[0].status, Status::Pass));
+    }
+
+    #[test]
+    fn reads_trimmed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("enabled");
+        std::fs::write(&path, "enabled\n").unwrap();
+
+        assert_eq!(read_trimmed(&path).as_deref(), Some("enabled"));
+        assert_eq!(read_trimmed(dir.path().join("missing")), None);
+    }
+}
@@ -0,0 +1,251 @@
+use crate::base::{self, Artifact};
+use crate::config::Size;
+use crate::source::{self, Source};
+use crate::{fs::path_to_string, http};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::time::Duration;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+macro_rules! err {
+    ($($args:expr),+) => {
+        return Err(Error::new(format!($($args),+)))
+    };
+}
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct DownloadManager<'a> {
+    client: &'a http::Client,
+    concurrency: usize,
+}
+
+#[derive(Default)]
+struct Progress {
+    bytes: AtomicU64,
+    total: AtomicU64,
+    finished: AtomicUsize,
+}
+
+impl<'a> DownloadManager<'a> {
+    pub fn new(client: &'a http::Client, concurrency: usize) -> Self {
+        Self {
+            client,
+            concurrency: concurrency.max(1),
+        }
+    }
+
+    pub fn run(&self, artifacts: &[&Artifact]) -> Result<u64> {
+        let queue: VecDeque<(&Artifact, &Source)> = artifacts
+            .iter()
+            .filter_map(|a| a.source.as_ref().map(|s| (*a, s)))
+            .collect();
+        let count = queue.len();
+        let progress = Progress::default();
+        progress.total.store(
+            queue.iter().filter_map(|(a, _)| a.size).sum(),
+            Ordering::Relaxed,
+        );
+        let queue = Mutex::new(queue);
+        let failures = Mutex::new(vec![]);
+
+        std::thread::scope(|s| {
+            let (done, finished) = mpsc::channel::<()>();
+            s.spawn(|| report_progress(finished, &progress, count));
+
+            let workers: Vec<_> = (0..self.concurrency.min(count))
+                .map(|_| {
+                    s.spawn(|| loop {
+                        let next = queue.lock().unwrap_or_else(|e| e.into_inner()).pop_front();
+                        let (artifact, source) = match next {
+                            Some(n) => n,
+                            None => break,
+                        };
+
+                        if let Err(e) = self.download(artifact, source, &progress) {
+                            let failure = format!("`{}`: {}", source, e);
+                            failures
+                                .lock()
+                                .unwrap_or_else(|e| e.into_inner())
+                                .push(failure);
+                        }
+                        progress.finished.fetch_add(1, Ordering::Relaxed);
+                    })
+                })
+                .collect();
+
+            for worker in workers {
+                let _ = worker.join();
+            }
+            drop(done);
+        });
+
+        let failures = failures.into_inner().unwrap_or_else(|e| e.into_inner());
+        if !failures.is_empty() {
+            err!(
+                "{} of {} downloads failed:\n{}",
+                failures.len(),
+                count,
+                failures.join("\n")
+            );
+        }
+
+        Ok(progress.bytes.load(Ordering::Relaxed))
+    }
+
+    fn download(&self, artifact: &Artifact, source: &Source, progress: &Progress) -> Result<()> {
+        let (mut reader, size) = source.open(self.client)?;
+        if let (None, Some(size)) = (artifact.size, size) {
+            progress.total.fetch_add(size, Ordering::Relaxed);
+        }
+
+        if let Err(e) = write_file(&mut reader, artifact, progress) {
+            err!(
+                "Unable to write `{}`: {}",
+                path_to_string(&artifact.path),
+                e
+            );
+        }
+
+        Ok(base::verify_artifact(artifact)?)
+    }
+}
+
+pub struct Error {
+    error: String,
+}
+
+impl Error {
+    pub fn new<S: AsRef<str>>(error: S) -> Self {
+        let error = error.as_ref().to_owned();
+
+        Self { error }
+    }
+}
+
+impl From<source::Error> for Error {
+    fn from(e: source::Error) -> Self {
+        Error::new(format!("{}", e))
+    }
+}
+
+impl From<base::Error> for Error {
+    fn from(e: base::Error) -> Self {
+        Error::new(format!("{}", e))
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+fn write_file(
+    reader: &mut impl Read,
+    artifact: &Artifact,
+    progress: &Progress,
+) -> std::io::Result<()> {
+    let mut file = File::create(&artifact.path)?;
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            return file.flush();
+        }
+
+        file.write_all(&buffer[..read])?;
+        progress.bytes.fetch_add(read as u64, Ordering::Relaxed);
+    }
+}
+
+fn report_progress(finished: mpsc::Receiver<()>, progress: &Progress, count: usize) {
+    while let Err(mpsc::RecvTimeoutError::Timeout) = finished.recv_timeout(PROGRESS_INTERVAL) {
+        println!(
+            "... {} of {} downloaded, {} of {} files complete",
+            Size(progress.bytes.load(Ordering::Relaxed)),
+            Size(progress.total.load(Ordering::Relaxed)),
+            progress.finished.load(Ordering::Relaxed),
+            count
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::Checksum;
+    use std::path::Path;
+
+    const HELLO_SHA256: &str = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+
+    fn artifact(dir: &Path, name: &str, checksum: &str) -> Artifact {
+        std::fs::write(dir.join("mirror").join(name), "hello\n").unwrap();
+
+        Artifact {
+            path: dir.join("downloads").join(name),
+            source: Some(Source::File(dir.join("mirror").join(name))),
+            size: None,
+            checksum: Some(Checksum::Sha256(checksum.to_owned())),
+        }
+    }
+
+    fn setup() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("mirror")).unwrap();
+        std::fs::create_dir(dir.path().join("downloads")).unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn downloads_and_verifies_artifacts() {
+        let dir = setup();
+        let client = http::Client::builder().build().ok().unwrap();
+        let artifacts: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|n| artifact(dir.path(), n, HELLO_SHA256))
+            .collect();
+        let local = Artifact {
+            path: dir.path().join("mirror/a"),
+            source: None,
+            size: None,
+            checksum: None,
+        };
+
+        let manager = DownloadManager::new(&client, 2);
+        let bytes = manager
+            .run(&[&artifacts[0], &artifacts[1], &artifacts[2], &local])
+            .ok()
+            .unwrap();
+        assert_eq!(bytes, 18);
+        for a in &artifacts {
+            assert_eq!(std::fs::read(&a.path).unwrap(), b"hello\n");
+        }
+    }
+
+    #[test]
+    fn reports_every_failure() {
+        let dir = setup();
+        let client = http::Client::builder().build().ok().unwrap();
+        let good = artifact(dir.path(), "good", HELLO_SHA256);
+        let bad = artifact(dir.path(), "bad", "00");
+        let mut missing = artifact(dir.path(), "missing", HELLO_SHA256);
+        missing.source = Some(Source::File(dir.path().join("mirror/nothing")));
+
+        let e = DownloadManager::new(&client, 1)
+            .run(&[&bad, &good, &missing])
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(e.starts_with("2 of 3 downloads failed:"));
+        assert!(e.contains("Checksum of"));
+        assert!(e.contains("mirror/nothing"));
+        assert!(good.path.exists());
+    }
+}
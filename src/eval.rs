@@ -0,0 +1,294 @@
+use crate::chroot::{self, Chroot};
+use crate::nixos;
+use std::path::PathBuf;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+macro_rules! err {
+    ($($args:expr),+) => {
+        return Err(Error::new(format!($($args),+)))
+    };
+}
+
+pub struct Location {
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+}
+
+pub struct Diagnostic {
+    pub message: String,
+    pub location: Option<Location>,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.location {
+            Some(l) => write!(f, "{}:{}:{}: {}", l.file, l.line, l.column, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+pub fn check_configuration(chroot: &Chroot, remaps: &[(String, PathBuf)]) -> Result<()> {
+    let expression = format!(
+        "(import <nixpkgs/nixos> {{ configuration = {{ imports = [ {} <nixpkgs/nixos/modules/virtualisation/lxc-container.nix> ]; }}; }}).config.system.build.toplevel.drvPath",
+        nixos::CONFIGURATION
+    );
+
+    match chroot::output(chroot, ["nix-instantiate", "--eval", "-E", &expression]) {
+        Ok(_) => Ok({}),
+        Err(e) => err!("{}", parse_error(&format!("{}", e), remaps)),
+    }
+}
+
+pub fn check_flake(
+    chroot: &Chroot,
+    flake_dir: &str,
+    output: &str,
+    host_dir: PathBuf,
+) -> Result<()> {
+    let flake = format!("path:{}", flake_dir);
+    let installable = format!("{}#{}.drvPath", flake, output);
+
+    let e = match chroot::output(chroot, ["nix", "eval", "--raw", &installable]) {
+        Ok(_) => return Ok({}),
+        Err(e) => format!("{}", e),
+    };
+
+    let mut remaps = vec![(flake_dir.to_owned(), host_dir.clone())];
+    let metadata = chroot::output(chroot, ["nix", "flake", "metadata", "--json", &flake]);
+    if let Ok(metadata) = metadata {
+        // JSON is valid YAML
+        let metadata: serde_yaml::Value = serde_yaml::from_str(&metadata).unwrap_or_default();
+        if let Some(path) = metadata["path"].as_str() {
+            remaps.push((path.to_owned(), host_dir));
+        }
+    }
+
+    err!("{}", parse_error(&e, &remaps))
+}
+
+/// Nix 2.3 ends the message with its location, later versions print it on a line of its own.
+pub fn parse_error(output: &str, remaps: &[(String, PathBuf)]) -> Diagnostic {
+    let output = strip_ansi(output);
+    let lines: Vec<&str> = output.lines().map(str::trim).collect();
+
+    let start = lines.iter().rposition(|l| {
+        l.strip_prefix("error:")
+            .is_some_and(|m| !m.trim().is_empty())
+    });
+    let start = match start {
+        Some(s) => s,
+        None => {
+            let message = lines
+                .iter()
+                .find(|l| !l.is_empty() && !l.starts_with("= "))
+                .unwrap_or(&"evaluation failed without an error message");
+
+            return Diagnostic {
+                message: message.to_string(),
+                location: None,
+            };
+        }
+    };
+
+    let mut message = lines[start]["error:".len()..].trim().to_owned();
+    let mut location = None;
+
+    // Old format, the location ends the message
+    if let Some((m, at)) = message.rsplit_once(" at ") {
+        if let Some(l) = parse_location(at, remaps) {
+            message = m.to_owned();
+            location = Some(l);
+        }
+    }
+
+    let mut paragraph_ended = false;
+    for line in &lines[start + 1..] {
+        if location.is_some() {
+            break;
+        }
+        if line.is_empty() {
+            paragraph_ended = true;
+            continue;
+        }
+        if let Some(l) = line
+            .strip_prefix("at ")
+            .and_then(|at| parse_location(at, remaps))
+        {
+            location = Some(l);
+            break;
+        }
+        // Source excerpts, traces and hints follow the message
+        let epilogue = ["= ", "…", "(use"].iter().any(|p| line.starts_with(p));
+        if paragraph_ended || epilogue {
+            break;
+        }
+        // Messages spanning several lines, e.g. of options that don't exist
+        message.push('\n');
+        message.push_str(line);
+    }
+
+    Diagnostic { message, location }
+}
+
+pub struct Error {
+    error: String,
+}
+
+impl Error {
+    pub fn new<S: AsRef<str>>(error: S) -> Self {
+        let error = error.as_ref().to_owned();
+
+        Self { error }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+fn parse_location(at: &str, remaps: &[(String, PathBuf)]) -> Option<Location> {
+    let mut parts = at.trim_end_matches(':').rsplitn(3, ':');
+    let column = parts.next()?.parse().ok()?;
+    let line = parts.next()?.parse().ok()?;
+    let file = parts.next()?;
+
+    let file = remaps
+        .iter()
+        .find_map(|(prefix, host)| match file.strip_prefix(prefix.as_str())? {
+            "" => Some(host.clone()),
+            rest if rest.starts_with('/') => Some(host.join(&rest[1..])),
+            _ => None,
+        })
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_else(|| file.to_owned());
+
+    Some(Location { file, line, column })
+}
+
+fn strip_ansi(output: &str) -> String {
+    let mut stripped = String::with_capacity(output.len());
+    let mut chars = output.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\u{1b}' {
+            stripped.push(c);
+            continue;
+        }
+        // Skip `ESC [ <parameters> <final byte>`
+        if chars.next() == Some('[') {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        }
+    }
+
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remaps() -> Vec<(String, PathBuf)> {
+        vec![("/opt/lxc-imports/0".to_owned(), PathBuf::from("/home/u"))]
+    }
+
+    #[test]
+    fn parses_old_format() {
+        let output = "error: undefined variable 'foo' at /home/u/a.nix:3:5\n";
+
+        let diagnostic = parse_error(output, &[]);
+        assert_eq!(
+            diagnostic.to_string(),
+            "/home/u/a.nix:3:5: undefined variable 'foo'"
+        );
+    }
+
+    #[test]
+    fn parses_new_format() {
+        let output = "\u{1b}[31;1merror:\u{1b}[0m undefined variable '\u{1b}[35;1mfoo\u{1b}[0m'\n\
+                      \n       at \u{1b}[35;1m/opt/lxc-imports/0/a.nix:3:5\u{1b}[0m:\n\
+                      \n            2| {\n            3|   x = foo;\n             |     ^\n";
+
+        let diagnostic = parse_error(output, &remaps());
+        assert_eq!(
+            diagnostic.to_string(),
+            "/home/u/a.nix:3:5: undefined variable 'foo'"
+        );
+    }
+
+    #[test]
+    fn reports_last_error_of_trace() {
+        let output = "error:\n       … while evaluating the attribute 'drvPath'\n\
+                      \n         at /nix/store/x-source/lib/customisation.nix:10:7:\n\
+                      \n       error: attribute 'hello' missing\n\
+                      \n       at /opt/lxc-imports/0/b.nix:7:3:\n\
+                      \n            7|   hello;\n";
+
+        let diagnostic = parse_error(output, &remaps());
+        assert_eq!(
+            diagnostic.to_string(),
+            "/home/u/b.nix:7:3: attribute 'hello' missing"
+        );
+    }
+
+    #[test]
+    fn keeps_messages_spanning_several_lines() {
+        let output = "error: The option `services.foo' does not exist. Definition values:\n\
+                      - In `/lxc.nix': true\n\
+                      (use '--show-trace' to show detailed location information)\n";
+
+        let diagnostic = parse_error(output, &[]);
+        assert!(diagnostic.location.is_none());
+        assert_eq!(
+            diagnostic.message,
+            "The option `services.foo' does not exist. Definition values:\n- In `/lxc.nix': true"
+        );
+    }
+
+    #[test]
+    fn falls_back_to_first_line() {
+        let diagnostic = parse_error("\nsomething went wrong\n", &[]);
+        assert_eq!(diagnostic.message, "something went wrong");
+
+        let diagnostic = parse_error("", &[]);
+        assert_eq!(
+            diagnostic.message,
+            "evaluation failed without an error message"
+        );
+    }
+
+    #[test]
+    fn remaps_locations() {
+        let remaps = remaps();
+
+        let location = parse_location("/opt/lxc-imports/0/a.nix:1:2:", &remaps).unwrap();
+        assert_eq!(location.file, "/home/u/a.nix");
+        assert_eq!((location.line, location.column), (1, 2));
+
+        let location = parse_location("/opt/lxc-imports/0:4:1", &remaps).unwrap();
+        assert_eq!(location.file, "/home/u");
+
+        let location = parse_location("/opt/lxc-imports/01/a.nix:1:1", &remaps).unwrap();
+        assert_eq!(location.file, "/opt/lxc-imports/01/a.nix");
+
+        assert!(parse_location("/lxc.nix:x:1", &remaps).is_none());
+        assert!(parse_location("somewhere", &remaps).is_none());
+    }
+
+    #[test]
+    fn strips_ansi_escapes() {
+        assert_eq!(
+            strip_ansi("\u{1b}[1;31merror:\u{1b}[0m plain"),
+            "error: plain"
+        );
+        assert_eq!(strip_ansi("no escapes"), "no escapes");
+    }
+}
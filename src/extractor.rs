@@ -1,8 +1,466 @@
-pub fn extract<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<()> {
-    let file = std::fs::File::open(&path)?;
-    let decoder = flate2::read::GzDecoder::new(file);
-    let mut archive = tar::Archive::new(decoder);
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Component, Path, PathBuf};
+use tar::EntryType;
 
-    let dst = path.as_ref().parent().unwrap();
-    archive.unpack(dst)
+enum Format {
+    Gzip,
+    Xz,
+    Zstd,
+    Bzip2,
+    Tar,
+}
+
+#[derive(Default)]
+pub struct Summary {
+    pub files: u64,
+    pub bytes: u64,
+    pub directories: u64,
+    pub symlinks: u64,
+    pub hardlinks: u64,
+    pub devices: u64,
+    pub fifos: u64,
+}
+
+impl Summary {
+    pub fn entries(&self) -> u64 {
+        self.files + self.directories + self.symlinks + self.hardlinks + self.devices + self.fifos
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} entries: {} files ({} bytes), {} directories, {} symlinks, {} hardlinks, {} device nodes, {} fifos",
+            self.entries(),
+            self.files,
+            self.bytes,
+            self.directories,
+            self.symlinks,
+            self.hardlinks,
+            self.devices,
+            self.fifos
+        )
+    }
+}
+
+pub fn extract<P: AsRef<Path>, D: AsRef<Path>>(path: P, destination: D) -> Result<Summary> {
+    let mut file = File::open(&path)?;
+    let format = detect_format(&mut file)?;
+
+    std::fs::create_dir_all(&destination)?;
+    let destination = destination.as_ref().canonicalize()?;
+
+    match format {
+        Format::Gzip => unpack(flate2::read::MultiGzDecoder::new(file), &destination),
+        Format::Xz => unpack(xz2::read::XzDecoder::new(file), &destination),
+        Format::Zstd => unpack(zstd::stream::read::Decoder::new(file)?, &destination),
+        Format::Bzip2 => unpack(bzip2::read::BzDecoder::new(file), &destination),
+        Format::Tar => unpack(file, &destination),
+    }
+}
+
+fn unpack<R: Read>(reader: R, destination: &Path) -> Result<Summary> {
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(true);
+    archive.set_preserve_mtime(true);
+    archive.set_unpack_xattrs(true);
+    archive.set_overwrite(true);
+
+    let mut summary = Summary::default();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = validate_path(&entry.path()?)?;
+        let kind = entry.header().entry_type();
+
+        match kind {
+            EntryType::Symlink => {
+                if let Some(target) = entry.link_name()? {
+                    validate_symlink(&path, &target)?;
+                }
+                summary.symlinks += 1;
+            }
+            EntryType::Link => {
+                if let Some(target) = entry.link_name()? {
+                    validate_path(&target)?;
+                }
+                summary.hardlinks += 1;
+            }
+            EntryType::Char | EntryType::Block | EntryType::Fifo => {
+                create_special_file(&entry, destination, &path)?;
+                match kind {
+                    EntryType::Fifo => summary.fifos += 1,
+                    _ => summary.devices += 1,
+                }
+                continue;
+            }
+            EntryType::Directory => summary.directories += 1,
+            EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse => {
+                summary.files += 1;
+                summary.bytes += entry.size();
+            }
+            _ => {}
+        }
+
+        if !entry.unpack_in(destination)? {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Refusing to extract `{}`", path.display()),
+            ));
+        }
+    }
+
+    Ok(summary)
+}
+
+fn validate_path(path: &Path) -> Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(c) => normalized.push(c),
+            Component::CurDir => {}
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Archive entry `{}` has an absolute path", path.display()),
+                ))
+            }
+            Component::ParentDir => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Archive entry `{}` contains `..`", path.display()),
+                ))
+            }
+        }
+    }
+
+    Ok(normalized)
+}
+
+/// Absolute targets resolve against the chroot at runtime, only relative ones can escape.
+fn validate_symlink(path: &Path, target: &Path) -> Result<()> {
+    if target.is_absolute() {
+        return Ok({});
+    }
+
+    let mut depth = path.components().count().saturating_sub(1);
+    for component in target.components() {
+        match component {
+            Component::ParentDir if depth == 0 => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Symlink `{}` points outside of the archive: `{}`",
+                        path.display(),
+                        target.display()
+                    ),
+                ))
+            }
+            Component::ParentDir => depth -= 1,
+            Component::Normal(_) => depth += 1,
+            _ => {}
+        }
+    }
+
+    Ok({})
+}
+
+fn create_special_file<R: Read>(
+    entry: &tar::Entry<R>,
+    destination: &Path,
+    path: &Path,
+) -> Result<()> {
+    let header = entry.header();
+    let (file_type, major, minor) = match header.entry_type() {
+        EntryType::Char => (
+            libc::S_IFCHR,
+            header.device_major()?.unwrap_or(0),
+            header.device_minor()?.unwrap_or(0),
+        ),
+        EntryType::Block => (
+            libc::S_IFBLK,
+            header.device_major()?.unwrap_or(0),
+            header.device_minor()?.unwrap_or(0),
+        ),
+        _ => (libc::S_IFIFO, 0, 0),
+    };
+    let mode = header.mode()? & 0o7777;
+
+    let path = destination.join(path);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+        if !parent.canonicalize()?.starts_with(destination) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Archive entry `{}` would be extracted outside of `{}`",
+                    path.display(),
+                    destination.display()
+                ),
+            ));
+        }
+    }
+    match std::fs::remove_file(&path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: `c_path` is a valid NUL-terminated string that outlives the calls.
+    unsafe {
+        if libc::mknod(
+            c_path.as_ptr(),
+            file_type | mode as libc::mode_t,
+            libc::makedev(major, minor),
+        ) != 0
+        {
+            return Err(Error::last_os_error());
+        }
+        if libc::lchown(c_path.as_ptr(), header.uid()? as _, header.gid()? as _) != 0 {
+            return Err(Error::last_os_error());
+        }
+        // mknod() is subject to umask, so permissions have to be set explicitly
+        if libc::chmod(c_path.as_ptr(), mode as libc::mode_t) != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+
+    Ok({})
+}
+
+pub fn summarize<P: AsRef<Path>>(directory: P) -> Result<Summary> {
+    let mut summary = Summary::default();
+    let mut pending = vec![directory.as_ref().to_owned()];
+
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let file_type = metadata.file_type();
+
+            if file_type.is_dir() {
+                summary.directories += 1;
+                pending.push(entry.path());
+            } else if file_type.is_symlink() {
+                summary.symlinks += 1;
+            } else if file_type.is_file() {
+                summary.files += 1;
+                summary.bytes += metadata.len();
+            } else if file_type.is_fifo() {
+                summary.fifos += 1;
+            } else {
+                summary.devices += 1;
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+fn detect_format(file: &mut File) -> Result<Format> {
+    let mut header = [0u8; 512];
+    let mut length = 0;
+    while length < header.len() {
+        match file.read(&mut header[length..])? {
+            0 => break,
+            n => length += n,
+        }
+    }
+    file.seek(SeekFrom::Start(0))?;
+
+    let header = &header[..length];
+    // Old tar formats have no magic bytes of their own
+    Ok(if header.starts_with(&[0x1f, 0x8b]) {
+        Format::Gzip
+    } else if header.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        Format::Xz
+    } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Format::Zstd
+    } else if header.starts_with(b"BZh") {
+        Format::Bzip2
+    } else {
+        Format::Tar
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn tarball() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(6);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_entry_type(EntryType::Regular);
+        builder
+            .append_data(&mut header, "etc/hostname", &b"hello\n"[..])
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn compressed(format: &str) -> Vec<u8> {
+        let tar = tarball();
+        match format {
+            "gzip" => {
+                let mut e = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+                e.write_all(&tar).unwrap();
+                e.finish().unwrap()
+            }
+            "xz" => {
+                let mut e = xz2::write::XzEncoder::new(Vec::new(), 6);
+                e.write_all(&tar).unwrap();
+                e.finish().unwrap()
+            }
+            "zstd" => zstd::stream::encode_all(&tar[..], 0).unwrap(),
+            "bzip2" => {
+                let mut e = bzip2::write::BzEncoder::new(Vec::new(), Default::default());
+                e.write_all(&tar).unwrap();
+                e.finish().unwrap()
+            }
+            _ => tar,
+        }
+    }
+
+    #[test]
+    fn detects_formats_by_magic_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for format in ["gzip", "xz", "zstd", "bzip2", "tar"] {
+            let path = dir.path().join(format);
+            std::fs::write(&path, compressed(format)).unwrap();
+
+            let mut file = File::open(&path).unwrap();
+            let detected = match detect_format(&mut file).unwrap() {
+                Format::Gzip => "gzip",
+                Format::Xz => "xz",
+                Format::Zstd => "zstd",
+                Format::Bzip2 => "bzip2",
+                Format::Tar => "tar",
+            };
+            assert_eq!(detected, format);
+            assert_eq!(file.stream_position().unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn extracts_compressed_tarballs() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for format in ["gzip", "xz", "zstd", "bzip2", "tar"] {
+            let path = dir.path().join(format!("{}.archive", format));
+            std::fs::write(&path, compressed(format)).unwrap();
+
+            let rootfs = dir.path().join(format);
+            let summary = extract(&path, &rootfs).unwrap();
+            assert_eq!((summary.files, summary.bytes), (1, 6));
+            assert_eq!(
+                std::fs::read(rootfs.join("etc/hostname")).unwrap(),
+                b"hello\n"
+            );
+        }
+    }
+
+    #[test]
+    fn extracts_old_tarballs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_old();
+        header.set_size(6);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        builder
+            .append_data(&mut header, "etc/hostname", &b"hello\n"[..])
+            .unwrap();
+        let path = dir.path().join("v7.tar");
+        std::fs::write(&path, builder.into_inner().unwrap()).unwrap();
+
+        let summary = extract(&path, dir.path().join("rootfs")).unwrap();
+        assert_eq!((summary.files, summary.bytes), (1, 6));
+    }
+
+    #[test]
+    fn extracts_multi_member_gzip() {
+        let dir = tempfile::tempdir().unwrap();
+        let tar = tarball();
+        let mut archive = vec![];
+        for member in [&tar[..512], &tar[512..]] {
+            let mut e = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+            e.write_all(member).unwrap();
+            archive.extend(e.finish().unwrap());
+        }
+        let path = dir.path().join("pigz.tar.gz");
+        std::fs::write(&path, archive).unwrap();
+
+        let rootfs = dir.path().join("rootfs");
+        extract(&path, &rootfs).unwrap();
+        assert_eq!(
+            std::fs::read(rootfs.join("etc/hostname")).unwrap(),
+            b"hello\n"
+        );
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rootfs.zip");
+        let mut zip = b"PK\x03\x04".to_vec();
+        zip.resize(1024, 0xff);
+        std::fs::write(&path, zip).unwrap();
+
+        assert!(extract(&path, dir.path().join("rootfs")).is_err());
+    }
+
+    #[test]
+    fn normalizes_entry_paths() {
+        assert_eq!(
+            validate_path(Path::new("./etc/./hostname")).unwrap(),
+            PathBuf::from("etc/hostname")
+        );
+        assert!(validate_path(Path::new("/etc/passwd")).is_err());
+        assert!(validate_path(Path::new("etc/../../passwd")).is_err());
+    }
+
+    #[test]
+    fn rejects_symlinks_escaping_the_archive() {
+        let link = Path::new("usr/lib/libc.so");
+
+        assert!(validate_symlink(link, Path::new("/lib/libc.so")).is_ok());
+        assert!(validate_symlink(link, Path::new("../../lib/libc.so")).is_ok());
+        assert!(validate_symlink(link, Path::new("../../../etc/shadow")).is_err());
+        assert!(validate_symlink(link, Path::new("x/../../../../etc")).is_err());
+    }
+
+    #[test]
+    fn refuses_entries_with_parent_components() {
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..12].copy_from_slice(b"../etc/owned");
+        header.set_size(0);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_entry_type(EntryType::Regular);
+        header.set_cksum();
+
+        let mut archive = header.as_bytes().to_vec();
+        archive.extend_from_slice(&[0; 1024]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let e = unpack(&archive[..], dir.path()).err().unwrap();
+        assert!(e.to_string().contains("contains `..`"));
+        assert!(!dir.path().join("../etc/owned").exists());
+    }
 }
@@ -1,11 +1,66 @@
-use std::io::{Error, ErrorKind, Result};
-use std::path::Path;
+use crate::time::{format_utc, now};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
-pub fn create_work_dir<P: AsRef<Path>>(path: P) -> Result<()> {
+const WORK_DIR_LAYOUT: [&str; 5] = ["downloads", "rootfs", "output", "logs", "state"];
+
+const WORK_DIR_LOCK: &str = ".lock";
+
+pub struct WorkDir {
+    root: PathBuf,
+    lock: Lock,
+}
+
+impl WorkDir {
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn lock(&self) -> &Lock {
+        &self.lock
+    }
+
+    pub fn downloads(&self) -> PathBuf {
+        self.root.join("downloads")
+    }
+
+    pub fn rootfs(&self) -> PathBuf {
+        self.root.join("rootfs")
+    }
+
+    pub fn output(&self) -> PathBuf {
+        self.root.join("output")
+    }
+
+    pub fn state(&self) -> PathBuf {
+        self.root.join("state")
+    }
+}
+
+pub fn create_work_dir<P: AsRef<Path>>(path: P) -> Result<WorkDir> {
+    let work_dir = init_work_dir(path)?;
+
+    match is_empty_dir(&work_dir.rootfs()) {
+        Ok(true) => Ok(work_dir),
+        // TODO: ErrorKind::DirectoryNotEmpty
+        Ok(false) => Err(Error::other(format!(
+            "`{}` is not empty, it is probably left over from a previous build and has to be removed",
+            path_to_string(work_dir.rootfs())
+        ))),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn init_work_dir<P: AsRef<Path>>(path: P) -> Result<WorkDir> {
     let wd = path.as_ref();
 
     if !wd.exists() {
-        match std::fs::create_dir_all(&wd) {
+        match std::fs::create_dir_all(wd) {
             Ok(_) => {}
             Err(e) => {
                 return Err(Error::new(
@@ -21,40 +76,279 @@ pub fn create_work_dir<P: AsRef<Path>>(path: P) -> Result<()> {
     }
 
     if !wd.is_dir() {
-        return Err(Error::new(
-            // TODO: ErrorKind::NotADirectory,
-            ErrorKind::Other,
-            format!("`{}` is not a directory", path_to_string(wd)),
-        ));
+        // TODO: ErrorKind::NotADirectory
+        return Err(Error::other(format!(
+            "`{}` is not a directory",
+            path_to_string(wd)
+        )));
     }
 
-    match is_empty_dir(wd) {
-        Ok(true) => Ok({}),
-        Ok(false) => {
+    let lock = Lock::acquire(wd.join(WORK_DIR_LOCK))?;
+    validate_work_dir(wd)?;
+
+    let work_dir = WorkDir {
+        root: wd.to_owned(),
+        lock,
+    };
+    for dir in WORK_DIR_LAYOUT {
+        let path = work_dir.root.join(dir);
+        if let Err(e) = std::fs::create_dir_all(&path) {
             return Err(Error::new(
-                // TODO: ErrorKind::DirectoryNotEmpty,
-                ErrorKind::Other,
-                format!("Working directory `{}` is not empty", path_to_string(wd)),
+                e.kind(),
+                format!("Unable to create `{}`: {}", path_to_string(&path), e),
             ));
         }
-        Err(e) => Err(e),
     }
+
+    Ok(work_dir)
 }
 
-fn is_empty_dir(path: &Path) -> Result<bool> {
-    match path.read_dir() {
-        Ok(mut dir) => Ok(dir.next().is_none()),
+fn validate_work_dir(wd: &Path) -> Result<()> {
+    let entries = match wd.read_dir() {
+        Ok(d) => d,
         Err(e) => {
+            return Err(Error::new(
+                e.kind(),
+                format!("Unable to access directory `{}`: {}", path_to_string(wd), e),
+            ))
+        }
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == WORK_DIR_LOCK {
+            continue;
+        }
+
+        let expected = WORK_DIR_LAYOUT.iter().any(|d| name == *d);
+
+        if !expected {
+            return Err(Error::other(format!(
+                "Working directory `{}` contains an unexpected entry `{}`",
+                path_to_string(wd),
+                name.to_string_lossy()
+            )));
+        }
+
+        if !entry.file_type()?.is_dir() {
+            return Err(Error::other(format!(
+                "`{}` is expected to be a directory",
+                path_to_string(entry.path())
+            )));
+        }
+    }
+
+    Ok({})
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LockOwner {
+    pid: u32,
+    host: String,
+    started: u64,
+}
+
+impl std::fmt::Display for LockOwner {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "pid {} on host `{}` since {}",
+            self.pid,
+            self.host,
+            format_utc(self.started)
+        )
+    }
+}
+
+/// A lock file with a dead owner that can nevertheless be locked is stale and cleared.
+pub struct Lock {
+    file: File,
+    path: PathBuf,
+    stale_owner: Option<LockOwner>,
+}
+
+impl Lock {
+    pub fn acquire<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+        {
+            Ok(f) => f,
+            Err(e) => {
+                return Err(Error::new(
+                    e.kind(),
+                    format!("Unable to open lock file `{}`: {}", path_to_string(path), e),
+                ))
+            }
+        };
+
+        // SAFETY: the file descriptor is owned by `file` and stays open during the call.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = Error::last_os_error();
+            if e.kind() != ErrorKind::WouldBlock {
+                return Err(Error::new(
+                    e.kind(),
+                    format!("Unable to lock `{}`: {}", path_to_string(path), e),
+                ));
+            }
+
+            return Err(Error::new(
+                ErrorKind::WouldBlock,
+                match read_lock_owner(&mut file) {
+                    Some(owner) => format!(
+                        "`{}` is locked by {}, another build is probably running",
+                        path_to_string(path),
+                        owner
+                    ),
+                    None => format!(
+                        "`{}` is locked by another process, another build is probably running",
+                        path_to_string(path)
+                    ),
+                },
+            ));
+        }
+
+        let stale_owner = read_lock_owner(&mut file);
+        let owner = LockOwner {
+            pid: std::process::id(),
+            host: hostname(),
+            started: now(),
+        };
+        if let Err(e) = write_lock_owner(&mut file, &owner) {
             return Err(Error::new(
                 e.kind(),
                 format!(
-                    "Unable to access directory `{}`: {}",
+                    "Unable to write lock file `{}`: {}",
                     path_to_string(path),
                     e
                 ),
-            ))
+            ));
         }
+
+        Ok(Self {
+            file,
+            path: path.to_owned(),
+            stale_owner,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
     }
+
+    pub fn stale_owner(&self) -> Option<&LockOwner> {
+        self.stale_owner.as_ref()
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // The file itself is kept, removing it would let another process lock a different inode
+        // while someone still waits on the old one. Emptying it marks the lock as released.
+        let _ = self.file.set_len(0);
+    }
+}
+
+fn read_lock_owner(file: &mut File) -> Option<LockOwner> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+
+    serde_yaml::from_str(&contents).ok()
+}
+
+fn write_lock_owner(file: &mut File, owner: &LockOwner) -> Result<()> {
+    let contents = match serde_yaml::to_string(owner) {
+        Ok(c) => c,
+        Err(e) => return Err(Error::new(ErrorKind::InvalidData, format!("{}", e))),
+    };
+
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()
+}
+
+fn hostname() -> String {
+    match std::fs::read_to_string("/proc/sys/kernel/hostname") {
+        Ok(h) => h.trim().to_owned(),
+        Err(_) => "unknown".to_owned(),
+    }
+}
+
+pub struct FsStats {
+    pub free_bytes: u64,
+    pub free_inodes: u64,
+    pub has_inode_limit: bool,
+    pub noexec: bool,
+    pub nodev: bool,
+}
+
+pub fn fs_stats<P: AsRef<Path>>(path: P) -> Result<FsStats> {
+    let c_path = std::ffi::CString::new(path.as_ref().as_os_str().as_bytes())?;
+    // SAFETY: `statvfs` is a plain C struct, for which all zero bytes are a valid value.
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    // SAFETY: `c_path` is a valid NUL-terminated string and `stats` is a properly sized buffer.
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stats) } != 0 {
+        let e = Error::last_os_error();
+        return Err(Error::new(
+            e.kind(),
+            format!(
+                "Unable to query filesystem of `{}`: {}",
+                path_to_string(path),
+                e
+            ),
+        ));
+    }
+
+    Ok(FsStats {
+        free_bytes: stats.f_bavail as u64 * stats.f_frsize as u64,
+        free_inodes: stats.f_favail as u64,
+        has_inode_limit: stats.f_files != 0,
+        noexec: stats.f_flag & libc::ST_NOEXEC != 0,
+        nodev: stats.f_flag & libc::ST_NODEV != 0,
+    })
+}
+
+fn is_empty_dir(path: &Path) -> Result<bool> {
+    match path.read_dir() {
+        Ok(mut dir) => Ok(dir.next().is_none()),
+        Err(e) => Err(Error::new(
+            e.kind(),
+            format!(
+                "Unable to access directory `{}`: {}",
+                path_to_string(path),
+                e
+            ),
+        )),
+    }
+}
+
+pub fn copy_dir<S: AsRef<Path>, D: AsRef<Path>>(source: S, destination: D) -> Result<()> {
+    let mut source = source.as_ref().as_os_str().to_owned();
+    source.push("/.");
+
+    let result = Command::new("cp")
+        .arg("-a")
+        .arg("--")
+        .arg(&source)
+        .arg(destination.as_ref())
+        .output()?;
+
+    if !result.status.success() {
+        return Err(Error::other(format!(
+            "`cp` failed: {}",
+            String::from_utf8_lossy(&result.stderr).trim()
+        )));
+    }
+
+    Ok({})
 }
 
 pub fn path_to_string<P: AsRef<Path>>(path: P) -> String {
@@ -65,3 +359,75 @@ pub fn path_to_string<P: AsRef<Path>>(path: P) -> String {
         None => path.to_string_lossy().into(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn creates_work_dir_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let wd = create_work_dir(dir.path().join("work")).unwrap();
+
+        for d in WORK_DIR_LAYOUT {
+            assert!(wd.root().join(d).is_dir());
+        }
+        assert_eq!(wd.rootfs(), dir.path().join("work/rootfs"));
+    }
+
+    #[test]
+    fn rejects_unexpected_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "").unwrap();
+
+        let e = create_work_dir(dir.path()).err().unwrap();
+        assert!(e.to_string().contains("unexpected entry `notes.txt`"));
+
+        std::fs::remove_file(dir.path().join("notes.txt")).unwrap();
+        std::fs::write(dir.path().join("rootfs"), "").unwrap();
+        let e = create_work_dir(dir.path()).err().unwrap();
+        assert!(e.to_string().ends_with("is expected to be a directory"));
+    }
+
+    #[test]
+    fn rejects_leftover_rootfs() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("rootfs/etc")).unwrap();
+
+        let e = create_work_dir(dir.path()).err().unwrap();
+        assert!(e.to_string().contains("left over from a previous build"));
+        assert!(init_work_dir(dir.path()).is_ok());
+    }
+
+    #[test]
+    fn locks_exclusively() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(WORK_DIR_LOCK);
+
+        let lock = Lock::acquire(&path).unwrap();
+        assert!(lock.stale_owner().is_none());
+
+        let e = Lock::acquire(&path).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::WouldBlock);
+        assert!(e
+            .to_string()
+            .contains(&format!("pid {}", std::process::id())));
+
+        drop(lock);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        assert!(Lock::acquire(&path).is_ok());
+    }
+
+    #[test]
+    fn clears_stale_locks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(WORK_DIR_LOCK);
+        std::fs::write(&path, "pid: 1\nhost: builder\nstarted: 0\n").unwrap();
+
+        let lock = Lock::acquire(&path).unwrap();
+        assert_eq!(
+            lock.stale_owner().unwrap().to_string(),
+            "pid 1 on host `builder` since 1970-01-01T00:00:00Z"
+        );
+    }
+}
@@ -0,0 +1,95 @@
+use crate::chroot::Chroot;
+use crate::config::{Config, Size};
+use crate::fs::path_to_string;
+use crate::mount::mount_kernel_filesystems;
+use crate::store::{self, SharedStore};
+use std::path::Path;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+macro_rules! err {
+    ($($args:expr),+) => {
+        return Err(Error::new(format!($($args),+)))
+    };
+}
+
+pub fn run(config: &Config, store: &SharedStore, rootfs: &Path) -> Result<()> {
+    let size = measure(store)?;
+
+    println!("Mounting shared Nix store...");
+    let _store_mount = match store.mount(rootfs) {
+        Ok(m) => {
+            println!(
+                "... OK: `{}` was mounted as `/nix`",
+                path_to_string(store.path())
+            );
+
+            m
+        }
+        Err(e) => err!("... ERROR: {}", e),
+    };
+
+    println!("Mounting Virtual Kernel File Systems...");
+    let _mounts = match mount_kernel_filesystems(rootfs) {
+        Ok(mts) => {
+            println!("... OK: devtmpfs, procfs, sysfs were successfully mounted");
+
+            mts
+        }
+        Err(e) => err!("... ERROR: {}", e),
+    };
+
+    let mut chroot = Chroot::new(rootfs);
+    for (key, value) in &config.environment {
+        chroot = chroot.env(key, value);
+    }
+
+    println!("Collecting garbage...");
+    match store::collect_garbage(&chroot, store, &config.nix.gc, size) {
+        Ok(_) => println!("... OK: garbage was successfully collected"),
+        Err(e) => err!("... ERROR: {}", e),
+    }
+
+    let pruned = measure(store)?;
+    println!(
+        "Shared Nix store shrank from {} to {}",
+        Size(size),
+        Size(pruned)
+    );
+
+    Ok({})
+}
+
+pub struct Error {
+    error: String,
+}
+
+impl Error {
+    pub fn new<S: AsRef<str>>(error: S) -> Self {
+        let error = error.as_ref().to_owned();
+
+        Self { error }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+fn measure(store: &SharedStore) -> Result<u64> {
+    println!("Measuring shared Nix store...");
+    match store.size() {
+        Ok(s) => {
+            println!(
+                "... OK: `{}` takes {}",
+                path_to_string(store.path()),
+                Size(s)
+            );
+
+            Ok(s)
+        }
+        Err(e) => err!("... ERROR: {}", e),
+    }
+}
@@ -0,0 +1,336 @@
+use crate::config::{HostFile, HostFileMode};
+use crate::fs::{copy_dir, path_to_string};
+use crate::mount::bind_mount;
+use std::path::{Component, Path, PathBuf};
+use sys_mount::{Mount, UnmountDrop};
+
+type Result<T> = core::result::Result<T, Error>;
+
+macro_rules! err {
+    ($($args:expr),+) => {
+        return Err(Error::new(format!($($args),+)))
+    };
+}
+
+/// Originals are moved aside and put back by `remove`, or when the value is dropped.
+#[derive(Default)]
+pub struct HostFiles {
+    mounts: Vec<UnmountDrop<Mount>>,
+    /// In installation order, as later ones may be nested in earlier ones.
+    targets: Vec<Target>,
+}
+
+struct Target {
+    path: PathBuf,
+    backup: Option<PathBuf>,
+    /// Mount points are never removed recursively.
+    mount_point: bool,
+}
+
+impl HostFiles {
+    pub fn remove(mut self) -> Result<()> {
+        self.remove_all()
+    }
+
+    fn remove_all(&mut self) -> Result<()> {
+        // Unmounts bind-mounted host files, so that their mount points can be removed
+        self.mounts.clear();
+
+        let mut errors = vec![];
+        while let Some(target) = self.targets.pop() {
+            if let Err(e) = restore(&target) {
+                errors.push(e.to_string());
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok({}),
+            false => err!("{}", errors.join("\n")),
+        }
+    }
+}
+
+impl Drop for HostFiles {
+    fn drop(&mut self) {
+        let _ = self.remove_all();
+    }
+}
+
+pub fn install<P: AsRef<Path>>(rootfs: P, files: &[HostFile]) -> Result<HostFiles> {
+    let rootfs = rootfs.as_ref();
+    let mut host_files = HostFiles::default();
+
+    for file in files {
+        let target = resolve_target(rootfs, file.target.as_ref().unwrap_or(&file.path))?;
+        let is_dir = match file.path.metadata() {
+            Ok(m) => m.is_dir(),
+            Err(e) => err!("Unable to access `{}`: {}", path_to_string(&file.path), e),
+        };
+
+        // Whatever is in place of the target, a symlink included, is replaced rather than
+        // written through, so that nothing outside of the rootfs is touched
+        host_files.targets.push(Target {
+            backup: move_aside(&target)?,
+            path: target.clone(),
+            mount_point: matches!(file.mode, HostFileMode::Bind),
+        });
+
+        match file.mode {
+            HostFileMode::Copy => copy(&file.path, &target, is_dir)?,
+            HostFileMode::Bind => host_files.mounts.push(bind(&file.path, &target, is_dir)?),
+        }
+    }
+
+    Ok(host_files)
+}
+
+pub struct Error {
+    error: String,
+}
+
+impl Error {
+    pub fn new<S: AsRef<str>>(error: S) -> Self {
+        let error = error.as_ref().to_owned();
+
+        Self { error }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+/// Neither `..` nor symlinks in the rootfs may lead outside of it.
+fn resolve_target(rootfs: &Path, target: &Path) -> Result<PathBuf> {
+    if !target.is_absolute() {
+        err!(
+            "Path `{}` inside the chroot environment has to be absolute",
+            path_to_string(target)
+        );
+    }
+
+    let mut relative = PathBuf::new();
+    for component in target.components() {
+        match component {
+            Component::Normal(c) => relative.push(c),
+            Component::RootDir | Component::CurDir => {}
+            _ => err!(
+                "Path `{}` inside the chroot environment must not contain `..`",
+                path_to_string(target)
+            ),
+        }
+    }
+
+    let path = rootfs.join(&relative);
+    let parent = match path.parent() {
+        Some(p) if relative.parent().is_some() => p,
+        _ => err!("`{}` can't be replaced", path_to_string(target)),
+    };
+    if let Err(e) = std::fs::create_dir_all(parent) {
+        err!("Unable to create `{}`: {}", path_to_string(parent), e);
+    }
+
+    let inside = match (parent.canonicalize(), rootfs.canonicalize()) {
+        (Ok(p), Ok(r)) => p.starts_with(r),
+        _ => false,
+    };
+    if !inside {
+        err!(
+            "`{}` resolves outside of the chroot environment",
+            path_to_string(target)
+        );
+    }
+
+    Ok(path)
+}
+
+fn copy(source: &Path, target: &Path, is_dir: bool) -> Result<()> {
+    let result = match is_dir {
+        true => std::fs::create_dir(target).and_then(|_| copy_dir(source, target)),
+        false => std::fs::copy(source, target).map(|_| {}),
+    };
+
+    match result {
+        Ok(_) => Ok({}),
+        Err(e) => err!(
+            "Unable to copy `{}` to `{}`: {}",
+            path_to_string(source),
+            path_to_string(target),
+            e
+        ),
+    }
+}
+
+fn bind(source: &Path, target: &Path, is_dir: bool) -> Result<UnmountDrop<Mount>> {
+    let result = match is_dir {
+        true => std::fs::create_dir(target),
+        false => std::fs::write(target, b""),
+    };
+    if let Err(e) = result {
+        err!("Unable to create `{}`: {}", path_to_string(target), e);
+    }
+
+    match bind_mount(source, target, true) {
+        Ok(m) => Ok(m),
+        Err(e) => err!(
+            "Unable to bind-mount `{}` onto `{}`: {}",
+            path_to_string(source),
+            path_to_string(target),
+            e
+        ),
+    }
+}
+
+fn move_aside(target: &Path) -> Result<Option<PathBuf>> {
+    if target.symlink_metadata().is_err() {
+        return Ok(None);
+    }
+
+    let backup = backup_path(target);
+    if backup.symlink_metadata().is_ok() {
+        err!(
+            "`{}` is left over from a previous build and has to be removed",
+            path_to_string(&backup)
+        );
+    }
+
+    match std::fs::rename(target, &backup) {
+        Ok(_) => Ok(Some(backup)),
+        Err(e) => err!("Unable to move `{}` aside: {}", path_to_string(target), e),
+    }
+}
+
+fn restore(target: &Target) -> Result<()> {
+    let path = &target.path;
+    // Symlinks aren't followed, so this never reaches outside of the rootfs
+    let result = match path.symlink_metadata() {
+        Ok(m) if m.is_dir() && target.mount_point => std::fs::remove_dir(path),
+        Ok(m) if m.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(_) => Ok(()),
+    };
+    if let Err(e) = result {
+        err!("Unable to remove `{}`: {}", path_to_string(path), e);
+    }
+
+    match target.backup.as_ref().map(|b| std::fs::rename(b, path)) {
+        Some(Err(e)) => err!(
+            "Unable to restore the original `{}`: {}",
+            path_to_string(path),
+            e
+        ),
+        _ => Ok({}),
+    }
+}
+
+fn backup_path(target: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(target.file_name().unwrap_or_default());
+    name.push(".nixop-original");
+
+    target.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Setup {
+        _dir: tempfile::TempDir,
+        host: PathBuf,
+        rootfs: PathBuf,
+        outside: PathBuf,
+    }
+
+    fn setup() -> Setup {
+        let dir = tempfile::tempdir().unwrap();
+        let host = dir.path().join("host");
+        let rootfs = dir.path().join("rootfs");
+        let outside = dir.path().join("outside");
+        for d in [&host, &rootfs.join("etc"), &outside] {
+            std::fs::create_dir_all(d).unwrap();
+        }
+        std::fs::write(host.join("hosts"), "host\n").unwrap();
+        std::fs::create_dir(host.join("ssl")).unwrap();
+        std::fs::write(host.join("ssl/cert.pem"), "cert\n").unwrap();
+
+        Setup {
+            _dir: dir,
+            host,
+            rootfs,
+            outside,
+        }
+    }
+
+    fn copy_of(path: PathBuf, target: &str) -> HostFile {
+        HostFile {
+            path,
+            target: Some(PathBuf::from(target)),
+            mode: HostFileMode::Copy,
+        }
+    }
+
+    #[test]
+    fn replaces_symlinked_targets() {
+        let s = setup();
+        std::os::unix::fs::symlink(&s.outside, s.rootfs.join("etc/ssl")).unwrap();
+        std::os::unix::fs::symlink(s.outside.join("hosts"), s.rootfs.join("etc/hosts")).unwrap();
+        let files = [
+            copy_of(s.host.join("ssl"), "/etc/ssl"),
+            copy_of(s.host.join("hosts"), "/etc/hosts"),
+        ];
+
+        let host_files = install(&s.rootfs, &files).ok().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(s.rootfs.join("etc/ssl/cert.pem")).unwrap(),
+            "cert\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(s.rootfs.join("etc/hosts")).unwrap(),
+            "host\n"
+        );
+        assert_eq!(std::fs::read_dir(&s.outside).unwrap().count(), 0);
+
+        assert!(host_files.remove().is_ok());
+        assert_eq!(
+            std::fs::read_link(s.rootfs.join("etc/ssl")).unwrap(),
+            s.outside
+        );
+        assert_eq!(
+            std::fs::read_link(s.rootfs.join("etc/hosts")).unwrap(),
+            s.outside.join("hosts")
+        );
+    }
+
+    #[test]
+    fn removes_copies_when_dropped() {
+        let s = setup();
+        std::fs::write(s.rootfs.join("etc/hosts"), "original\n").unwrap();
+        let files = [
+            copy_of(s.host.join("hosts"), "/etc/hosts"),
+            copy_of(s.host.join("ssl"), "/etc/nix/ssl"),
+        ];
+
+        drop(install(&s.rootfs, &files).ok().unwrap());
+        assert_eq!(
+            std::fs::read_to_string(s.rootfs.join("etc/hosts")).unwrap(),
+            "original\n"
+        );
+        assert!(!s.rootfs.join("etc/nix/ssl").exists());
+        assert_eq!(std::fs::read_dir(s.rootfs.join("etc")).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn rejects_targets_outside_of_the_rootfs() {
+        let s = setup();
+        std::os::unix::fs::symlink(&s.outside, s.rootfs.join("opt")).unwrap();
+
+        for target in ["etc/hosts", "/etc/../hosts", "/opt/hosts", "/"] {
+            let files = [copy_of(s.host.join("hosts"), target)];
+            assert!(install(&s.rootfs, &files).is_err(), "{}", target);
+        }
+        assert_eq!(std::fs::read_dir(&s.outside).unwrap().count(), 0);
+    }
+}
@@ -1,12 +1,15 @@
-use bytes::Buf;
+use crate::throttle::{Throttle, ThrottledReader};
 use core::time::Duration;
-use reqwest::{IntoUrl, Url};
+use reqwest::{header, IntoUrl, StatusCode, Url};
 use std::io::Read;
+use std::net::IpAddr;
+use std::sync::Arc;
 
 pub type Result<T> = core::result::Result<T, Error>;
 
 pub struct ClientBuilder {
     builder: reqwest::blocking::ClientBuilder,
+    throttle: Option<Arc<Throttle>>,
 }
 
 impl ClientBuilder {
@@ -17,6 +20,7 @@ impl ClientBuilder {
                 .referer(false)
                 .use_rustls_tls()
                 .https_only(true),
+            throttle: None,
         }
     }
 
@@ -32,58 +36,222 @@ impl ClientBuilder {
         self
     }
 
+    pub fn add_root_certificates(mut self, pem: &[u8]) -> Result<Self> {
+        self.builder = self
+            .builder
+            .add_root_certificate(reqwest::Certificate::from_pem(pem)?);
+
+        Ok(self)
+    }
+
+    pub fn proxy(mut self, url: impl IntoUrl, no_proxy: &[String]) -> Result<Self> {
+        let url = url.into_url()?;
+        let rules: Vec<NoProxyRule> = no_proxy.iter().map(|r| NoProxyRule::new(r)).collect();
+
+        self.builder = self
+            .builder
+            .proxy(reqwest::Proxy::custom(move |u| match u.host_str() {
+                Some(h) if rules.iter().any(|r| r.matches(h)) => None,
+                _ => Some(url.clone()),
+            }));
+
+        Ok(self)
+    }
+
+    pub fn env_proxy(mut self, enabled: bool) -> Self {
+        if !enabled {
+            self.builder = self.builder.no_proxy();
+        }
+
+        self
+    }
+
+    pub fn allow_http(mut self, allowed: bool) -> Self {
+        self.builder = self.builder.https_only(!allowed);
+
+        self
+    }
+
+    pub fn rate_limit(mut self, bytes_per_second: u64) -> Self {
+        self.throttle = match bytes_per_second {
+            0 => None,
+            b => Some(Throttle::new(b)),
+        };
+
+        self
+    }
+
     pub fn build(self) -> Result<Client> {
-        Client::new(self.builder.build()?)
+        Client::new(self.builder.build()?, self.throttle)
     }
 }
 
+enum NoProxyRule {
+    Any,
+    Domain(String),
+    Network(IpAddr, u8),
+}
+
+impl NoProxyRule {
+    fn new(rule: &str) -> Self {
+        let rule = rule.trim();
+        if rule == "*" {
+            return Self::Any;
+        }
+
+        let (address, prefix) = match rule.split_once('/') {
+            Some((a, p)) => (a, p.parse().ok()),
+            None => (rule, None),
+        };
+        if let Ok(ip) = address
+            .trim_matches(|c| c == '[' || c == ']')
+            .parse::<IpAddr>()
+        {
+            let max_prefix = if ip.is_ipv4() { 32 } else { 128 };
+            return Self::Network(ip, prefix.unwrap_or(max_prefix).min(max_prefix));
+        }
+
+        Self::Domain(rule.trim_start_matches('.').to_ascii_lowercase())
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        let host = host.trim_matches(|c| c == '[' || c == ']');
+
+        match self {
+            Self::Any => true,
+            Self::Domain(d) => {
+                let host = host.to_ascii_lowercase();
+                host == *d || host.ends_with(&format!(".{}", d))
+            }
+            Self::Network(network, prefix) => match (host.parse::<IpAddr>(), network) {
+                (Ok(IpAddr::V4(h)), IpAddr::V4(n)) => {
+                    let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                    u32::from(h) & mask == u32::from(*n) & mask
+                }
+                (Ok(IpAddr::V6(h)), IpAddr::V6(n)) => {
+                    let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                    u128::from(h) & mask == u128::from(*n) & mask
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Client {
     client: reqwest::blocking::Client,
+    throttle: Option<Arc<Throttle>>,
 }
 
 impl Client {
-    fn new(client: reqwest::blocking::Client) -> Result<Self> {
-        Ok(Self { client })
+    fn new(client: reqwest::blocking::Client, throttle: Option<Arc<Throttle>>) -> Result<Self> {
+        Ok(Self { client, throttle })
     }
 
     pub fn builder() -> ClientBuilder {
         ClientBuilder::new()
     }
 
+    /// `304 Not Modified` is only accepted in reply to conditional requests.
     pub fn get(&self, req: GetRequest) -> Result<Response> {
-        let resp = self.client.get(req.url).send()?;
-
-        if !resp.status().is_success() {
-            return Err(Error::new(format!("HTTP error: {}", resp.status())));
+        let conditional = req.is_conditional();
+        let mut builder = self.client.get(req.url);
+        if let Some(etag) = req.if_none_match {
+            builder = builder.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(date) = req.if_modified_since {
+            builder = builder.header(header::IF_MODIFIED_SINCE, date);
         }
+        let resp = builder.send()?;
 
-        Ok(Response { inner: resp })
+        check_status(resp.status(), conditional)?;
+
+        Ok(Response {
+            inner: resp,
+            throttle: self.throttle.clone(),
+        })
     }
 }
 
 pub struct GetRequest {
     url: Url,
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
 }
 
 impl GetRequest {
     pub fn new(u: impl IntoUrl) -> Result<Self> {
         let url = u.into_url()?;
 
-        Ok(Self { url })
+        Ok(Self {
+            url,
+            if_none_match: None,
+            if_modified_since: None,
+        })
+    }
+
+    pub fn if_none_match<S: Into<String>>(mut self, etag: S) -> Self {
+        self.if_none_match = Some(etag.into());
+
+        self
+    }
+
+    pub fn if_modified_since<S: Into<String>>(mut self, date: S) -> Self {
+        self.if_modified_since = Some(date.into());
+
+        self
+    }
+
+    fn is_conditional(&self) -> bool {
+        self.if_none_match.is_some() || self.if_modified_since.is_some()
     }
 }
 
 pub struct Response {
     inner: reqwest::blocking::Response,
+    throttle: Option<Arc<Throttle>>,
 }
 
 impl Response {
-    pub fn as_text(self) -> Result<String> {
-        Ok(self.inner.text()?)
+    pub fn is_not_modified(&self) -> bool {
+        self.inner.status() == StatusCode::NOT_MODIFIED
+    }
+
+    pub fn etag(&self) -> Option<String> {
+        self.header(header::ETAG)
+    }
+
+    pub fn last_modified(&self) -> Option<String> {
+        self.header(header::LAST_MODIFIED)
+    }
+
+    fn header(&self, name: header::HeaderName) -> Option<String> {
+        self.inner
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_owned())
+    }
+
+    pub fn into_text(self) -> Result<String> {
+        let mut text = String::new();
+        if let Err(e) = self.into_reader()?.read_to_string(&mut text) {
+            return Err(Error::new(format!("Unable to read response: {}", e)));
+        }
+
+        Ok(text)
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        self.inner.content_length()
     }
 
-    pub fn as_reader(self) -> Result<impl Read> {
-        Ok(self.inner.bytes()?.reader())
+    pub fn into_reader(self) -> Result<Box<dyn Read + Send>> {
+        Ok(match self.throttle {
+            Some(t) => Box::new(ThrottledReader::new(self.inner, t)),
+            None => Box::new(self.inner),
+        })
     }
 }
 
@@ -108,3 +276,65 @@ impl std::fmt::Display for Error {
         write!(f, "{}", self.error)
     }
 }
+
+fn check_status(status: StatusCode, conditional: bool) -> Result<()> {
+    if status.is_success() || (conditional && status == StatusCode::NOT_MODIFIED) {
+        return Ok({});
+    }
+
+    Err(Error::new(format!("HTTP error: {}", status)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_domains_and_subdomains() {
+        let rule = NoProxyRule::new(" .Example.org ");
+
+        assert!(rule.matches("example.org"));
+        assert!(rule.matches("cache.EXAMPLE.org"));
+        assert!(!rule.matches("notexample.org"));
+        assert!(NoProxyRule::new("*").matches("nixos.org"));
+    }
+
+    #[test]
+    fn matches_networks() {
+        let rule = NoProxyRule::new("10.0.0.0/8");
+        assert!(rule.matches("10.1.2.3"));
+        assert!(!rule.matches("11.0.0.1"));
+        assert!(!rule.matches("::1"));
+
+        let rule = NoProxyRule::new("[fd00::]/16");
+        assert!(rule.matches("[fd00::1]"));
+        assert!(!rule.matches("fe80::1"));
+
+        let rule = NoProxyRule::new("192.0.2.1");
+        assert!(rule.matches("192.0.2.1"));
+        assert!(!rule.matches("192.0.2.2"));
+        assert!(NoProxyRule::new("0.0.0.0/0").matches("203.0.113.7"));
+    }
+
+    #[test]
+    fn accepts_not_modified_only_for_conditional_requests() {
+        assert!(check_status(StatusCode::OK, false).is_ok());
+        assert!(check_status(StatusCode::NOT_MODIFIED, true).is_ok());
+
+        let e = check_status(StatusCode::NOT_MODIFIED, false).err().unwrap();
+        assert_eq!(e.to_string(), "HTTP error: 304 Not Modified");
+        assert!(check_status(StatusCode::NOT_FOUND, true).is_err());
+    }
+
+    #[test]
+    fn detects_conditional_requests() {
+        let req = GetRequest::new("https://example.org/").ok().unwrap();
+        assert!(!req.is_conditional());
+        assert!(req.if_none_match("\"v1\"").is_conditional());
+
+        let req = GetRequest::new("https://example.org/").ok().unwrap();
+        assert!(req
+            .if_modified_since("Mon, 01 Jan 2024 00:00:00 GMT")
+            .is_conditional());
+    }
+}
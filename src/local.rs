@@ -0,0 +1,126 @@
+use crate::alpine::install_with_apk;
+use crate::arch::Arch;
+use crate::base::{self, Artifact, BaseSystemProvider, Checksum};
+use crate::chroot::Chroot;
+use crate::extractor::{summarize, Summary};
+use crate::fs::{copy_dir, path_to_string, WorkDir};
+use std::path::{Path, PathBuf};
+
+type Result<T> = core::result::Result<T, base::Error>;
+
+macro_rules! err {
+    ($($args:expr),+) => {
+        return Err(base::Error::new(format!($($args),+)))
+    };
+}
+
+const TARBALL_EXTENSIONS: [&str; 9] = [
+    ".tar", ".tar.gz", ".tgz", ".tar.xz", ".txz", ".tar.zst", ".tzst", ".tar.bz2", ".tbz2",
+];
+
+pub struct LocalRootfs {
+    path: PathBuf,
+    checksum: Option<Checksum>,
+}
+
+impl LocalRootfs {
+    pub fn new<P: AsRef<Path>>(path: P, checksum: Option<Checksum>) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+
+        if path.is_dir() {
+            if checksum.is_some() {
+                err!(
+                    "A checksum can only be verified for a rootfs tarball, but `{}` is a directory",
+                    path_to_string(&path)
+                );
+            }
+        } else if !is_tarball(&path) {
+            err!(
+                "`{}` is neither a directory nor a `{}` tarball",
+                path_to_string(&path),
+                TARBALL_EXTENSIONS.join("`, `")
+            );
+        }
+
+        Ok(Self { path, checksum })
+    }
+}
+
+impl BaseSystemProvider for LocalRootfs {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    fn resolve(&self, _: &Arch, _: &WorkDir) -> Result<Artifact> {
+        if !self.path.exists() {
+            err!("`{}` does not exist", path_to_string(&self.path));
+        }
+
+        Ok(Artifact {
+            path: self.path.clone(),
+            source: None,
+            size: None,
+            checksum: self.checksum.clone(),
+        })
+    }
+
+    fn extract(&self, artifact: &Artifact, rootfs: &Path) -> Result<Summary> {
+        if !artifact.path.is_dir() {
+            return base::extract_tarball(artifact, rootfs);
+        }
+
+        if let Err(e) = copy_dir(&artifact.path, rootfs) {
+            err!(
+                "Unable to copy `{}` into `{}`: {}",
+                path_to_string(&artifact.path),
+                path_to_string(rootfs),
+                e
+            );
+        }
+
+        match summarize(rootfs) {
+            Ok(s) => Ok(s),
+            Err(e) => err!("Unable to inspect `{}`: {}", path_to_string(rootfs), e),
+        }
+    }
+
+    fn install_packages(&self, chroot: &Chroot, packages: &[&str]) -> Result<()> {
+        if chroot.path().join("sbin").join("apk").exists() {
+            return Ok(install_with_apk(chroot, packages)?);
+        }
+
+        err!(
+            "Unable to find a supported package manager (`apk`) in `{}`",
+            path_to_string(chroot.path())
+        )
+    }
+}
+
+fn is_tarball(p: &Path) -> bool {
+    let name = path_to_string(p);
+
+    TARBALL_EXTENSIONS.iter().any(|e| name.ends_with(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_tarballs() {
+        assert!(is_tarball(Path::new("/srv/rootfs.tar.zst")));
+        assert!(is_tarball(Path::new("rootfs.tgz")));
+        assert!(!is_tarball(Path::new("rootfs.zip")));
+    }
+
+    #[test]
+    fn accepts_directories_and_tarballs_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let checksum = Some(Checksum::Sha256("00".to_owned()));
+
+        assert!(LocalRootfs::new(dir.path(), None).is_ok());
+        assert!(LocalRootfs::new(dir.path(), checksum.clone()).is_err());
+        assert!(LocalRootfs::new(dir.path().join("rootfs.tar.xz"), checksum).is_ok());
+        assert!(LocalRootfs::new(dir.path().join("rootfs.img"), None).is_err());
+    }
+}
@@ -1,12 +1,35 @@
+#![allow(clippy::unit_arg)]
+
 mod alpine;
 mod app;
 mod arch;
+mod args;
+mod base;
 mod chroot;
+mod config;
+mod dns;
+mod doctor;
+mod download;
+mod eval;
 mod extractor;
 mod fs;
+mod gc;
+mod host_files;
 mod http;
+mod local;
 mod mount;
 mod nix;
+mod nix_conf;
+mod nixos;
+mod preflight;
+mod report;
+mod source;
+mod store;
+mod throttle;
+mod time;
+
+use crate::args::Command;
+use crate::config::Config;
 
 macro_rules! abort {
     ($($msg:expr),+) => {
@@ -21,7 +44,27 @@ fn abort<S: AsRef<str>>(message: S) -> ! {
 }
 
 fn main() {
-    let app = match crate::app::init_app() {
+    let args = match crate::args::parse() {
+        Ok(args) => args,
+        Err(e) => abort!("{}", e),
+    };
+    let config = match Config::load(args.config) {
+        Ok(config) => config,
+        Err(e) => abort!("{}", e),
+    };
+    match args.command {
+        Command::Build => build(config),
+        Command::Doctor => {
+            if !crate::doctor::run(&config) {
+                abort!("Some checks failed, see above for details");
+            }
+        }
+        Command::Gc => gc(config),
+    }
+}
+
+fn build(config: Config) {
+    let app = match crate::app::init_app(config) {
         Ok(app) => app,
         Err(e) => abort!("Failed to initialize the application: {}", e),
     };
@@ -30,3 +73,13 @@ fn main() {
         Err(e) => abort!("{}", e),
     };
 }
+
+fn gc(config: Config) {
+    let app = match crate::app::init_app(config) {
+        Ok(app) => app,
+        Err(e) => abort!("Failed to initialize the application: {}", e),
+    };
+    if let Err(e) = app.gc() {
+        abort!("{}", e);
+    }
+}
@@ -58,6 +58,29 @@ pub fn mount_kernel_filesystems<P: AsRef<Path>>(path: P) -> Result<Vec<UnmountDr
     Ok(mounts)
 }
 
+pub fn bind_mount<S: AsRef<Path>, T: AsRef<Path>>(
+    source: S,
+    target: T,
+    read_only: bool,
+) -> Result<UnmountDrop<Mount>> {
+    let target = target.as_ref();
+    let mount = Mount::new(source, target, "none", MountFlags::BIND, None)?
+        .into_unmount_drop(UnmountFlags::DETACH);
+
+    // Bind mounts ignore MS_RDONLY initially, it only takes effect when remounting
+    if read_only {
+        Mount::new(
+            "",
+            target,
+            "none",
+            MountFlags::BIND | MountFlags::REMOUNT | MountFlags::RDONLY,
+            None,
+        )?;
+    }
+
+    Ok(mount)
+}
+
 fn mount_dev(p: PathBuf) -> Result<Mount> {
     Mount::new("devtmpfs", p, "devtmpfs", MountFlags::empty(), None)
 }
@@ -1,7 +1,12 @@
-use crate::chroot;
-use crate::fs::path_to_string;
-use std::io::prelude::*;
-use std::path::Path;
+use crate::chroot::{self, Chroot};
+use crate::extractor::extract;
+use crate::fs::{copy_dir, path_to_string};
+use crate::nix_conf::NixConf;
+use crate::nixos;
+use std::fs::Permissions;
+use std::io::ErrorKind;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 
 type Result<T> = core::result::Result<T, Error>;
 
@@ -21,74 +26,206 @@ macro_rules! err {
     };
 }
 
-pub fn install_nix<R: AsRef<Path>>(chroot: R) -> Result<()> {
-    let chroot = chroot.as_ref();
+pub const PINNED_NIXPKGS: &str = "/opt/nixpkgs";
 
-    configure_repositories(chroot)?;
-    update_repositories(chroot)?;
-    install_nix_package(chroot)?;
-    configure_nix(chroot)?;
-    update_channels(chroot)?;
+pub const PINNED_GENERATORS: &str = "/opt/nixos-generators";
 
-    Ok({})
+pub const FLAKE_DIR: &str = "/opt/flake";
+
+pub const NETRC: &str = "/etc/nix/netrc";
+
+pub const LOCAL_CACHES: &str = "/var/cache/nixops/substituters";
+
+/// Tarballs of the repository don't record a version in the derivation name.
+pub const UNKNOWN_VERSION: &str = "unknown";
+
+const DEFAULT_PROFILE: &str = "/nix/var/nix/profiles/default";
+
+pub const SECRET_KEY: &str = "/etc/nix/secret-key";
+
+pub const PUSH_CACHE: &str = "/var/cache/nixops/push";
+
+pub const RESULT: &str = "/opt/result";
+
+pub fn setup_nix(chroot: &Chroot, conf: &NixConf, channel: Option<&str>) -> Result<DefaultProfile> {
+    configure_nix(chroot, conf)?;
+    let profile = init_default_profile(chroot)?;
+    if let Some(channel) = channel {
+        update_channels(chroot, channel)?;
+    }
+
+    Ok(profile)
 }
 
-fn configure_repositories(chroot: &Path) -> Result<()> {
-    let mut repo_path = chroot.to_owned();
-    repo_path.push("etc");
-    repo_path.push("apk");
-    repo_path.push("repositories");
-    let repositories = format!(
-        "https://dl-cdn.alpinelinux.org/alpine/edge/main/\n\
-        https://dl-cdn.alpinelinux.org/alpine/edge/community/\n\
-        https://dl-cdn.alpinelinux.org/alpine/edge/testing/\n"
-    );
-    match std::fs::write(&repo_path, repositories) {
-        Ok(_) => {}
+/// Nix runs as root, so files with credentials are only accessible to root.
+pub fn restrict_secret(chroot: &Chroot, file: &str) -> Result<()> {
+    let path = chroot.path().join(file.trim_start_matches('/'));
+
+    let result = std::os::unix::fs::chown(&path, Some(0), Some(0))
+        .and_then(|_| std::fs::set_permissions(&path, Permissions::from_mode(0o600)));
+    match result {
+        Ok(_) => Ok({}),
         Err(e) => err!(
-            "Failed to update `{}` file: {}",
-            path_to_string(repo_path),
+            "Unable to restrict permissions of `{}`: {}",
+            path_to_string(&path),
             e
         ),
     }
+}
+
+pub fn unpack_expressions(chroot: &Chroot, tarball: &Path, target: &str) -> Result<()> {
+    let target = chroot.path().join(target.trim_start_matches('/'));
+    let staging = target.with_extension("unpack");
+
+    if let Err(e) = extract(tarball, &staging) {
+        err!("Unable to extract `{}`: {}", path_to_string(tarball), e);
+    }
+
+    // Tarballs of GitHub and channels contain a single top-level directory, e.g. `nixpkgs-<rev>`
+    let entries: Vec<PathBuf> = match std::fs::read_dir(&staging) {
+        Ok(d) => d.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+        Err(e) => err!("Unable to list `{}`: {}", path_to_string(&staging), e),
+    };
+    let root = match entries.as_slice() {
+        [dir] if dir.is_dir() => dir.clone(),
+        _ => staging.clone(),
+    };
+    if !root.join("default.nix").is_file() {
+        err!(
+            "`{}` doesn't contain Nix expressions, `default.nix` is missing",
+            path_to_string(tarball)
+        );
+    }
+
+    if let Err(e) = std::fs::rename(&root, &target) {
+        err!(
+            "Unable to move `{}` into `{}`: {}",
+            path_to_string(&root),
+            path_to_string(&target),
+            e
+        );
+    }
+    if staging.exists() {
+        if let Err(e) = std::fs::remove_dir_all(&staging) {
+            err!("Unable to remove `{}`: {}", path_to_string(&staging), e);
+        }
+    }
 
     Ok({})
 }
 
-fn update_repositories(chroot: &Path) -> Result<()> {
-    match chroot::execute(chroot, ["apk", "update"]) {
-        Ok(_) => Ok({}),
-        Err(e) => err!("Failed to install the `nix` package:\n{}", e),
+pub fn copy_flake(chroot: &Chroot, source: &Path) -> Result<()> {
+    if !source.join("flake.nix").is_file() {
+        err!("`{}` doesn't contain `flake.nix`", path_to_string(source));
+    }
+
+    let target = chroot.path().join(FLAKE_DIR.trim_start_matches('/'));
+    if let Err(e) = std::fs::create_dir_all(&target).and_then(|_| copy_dir(source, &target)) {
+        err!(
+            "Unable to copy `{}` into `{}`: {}",
+            path_to_string(source),
+            path_to_string(&target),
+            e
+        );
     }
+
+    Ok({})
 }
 
-fn install_nix_package(chroot: &Path) -> Result<()> {
-    match chroot::execute(chroot, ["apk", "add", "bash", "tar", "xz", "nix"]) {
-        Ok(_) => Ok({}),
-        Err(e) => err!("Failed to install the `nix` package:\n{}", e),
+pub fn build_flake(chroot: &Chroot, output: &str) -> Result<PathBuf> {
+    // The `path:` scheme doesn't require git, nor the flake to be a git repository
+    let installable = format!("path:{}#{}", FLAKE_DIR, output);
+
+    if let Err(e) = chroot::execute(chroot, ["nix", "build", &installable, "--out-link", RESULT]) {
+        err!("Failed to build `{}`:\n{}", installable, e);
     }
+
+    resolve_result(chroot)
+}
+
+fn resolve_result(chroot: &Chroot) -> Result<PathBuf> {
+    // The link points into the store of the chroot environment, not the host
+    let link = chroot.path().join(RESULT.trim_start_matches('/'));
+    match std::fs::read_link(&link) {
+        Ok(t) => Ok(chroot.path().join(t.strip_prefix("/").unwrap_or(&t))),
+        Err(e) => err!("Unable to read `{}`: {}", path_to_string(&link), e),
+    }
+}
+
+pub fn push_closure(chroot: &Chroot, installable: &str, url: &str, sign: bool) -> Result<()> {
+    if sign {
+        if let Err(e) = chroot::execute(
+            chroot,
+            [
+                "nix",
+                "store",
+                "sign",
+                "--key-file",
+                SECRET_KEY,
+                "--recursive",
+                installable,
+            ],
+        ) {
+            err!("Failed to sign `{}`:\n{}", installable, e);
+        }
+    }
+
+    if let Err(e) = chroot::execute(chroot, ["nix", "copy", "--to", url, installable]) {
+        err!("Failed to copy `{}` to `{}`:\n{}", installable, url, e);
+    }
+
+    Ok({})
+}
+
+pub fn flake_nixpkgs_revision(flake: &Path) -> Result<String> {
+    let path = flake.join("flake.lock");
+    let content = match std::fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(e) => err!("Unable to read `{}`: {}", path_to_string(&path), e),
+    };
+
+    // JSON is valid YAML
+    let lock: serde_yaml::Value = match serde_yaml::from_str(&content) {
+        Ok(l) => l,
+        Err(e) => err!("Unable to parse `{}`: {}", path_to_string(&path), e),
+    };
+
+    let nodes = &lock["nodes"];
+    let root = lock["root"].as_str().unwrap_or("root");
+    let revision = nodes[root]["inputs"]["nixpkgs"]
+        .as_str()
+        .and_then(|n| nodes[n]["locked"]["rev"].as_str());
+
+    Ok(revision.unwrap_or("unknown").to_owned())
 }
 
-fn configure_nix(chroot: &Path) -> Result<()> {
-    let mut nix_conf_path = chroot.to_owned();
+pub fn nixpkgs_revision(chroot: &Chroot) -> Result<String> {
+    let expression = r#"(import <nixpkgs/lib>).trivial.revisionWithDefault "unknown""#;
+
+    match chroot::output(chroot, ["nix-instantiate", "--eval", "-E", expression]) {
+        Ok(o) => Ok(o.trim().trim_matches('"').to_owned()),
+        Err(e) => err!("Failed to determine nixpkgs revision:\n{}", e),
+    }
+}
+
+fn configure_nix(chroot: &Chroot, conf: &NixConf) -> Result<()> {
+    let mut nix_conf_path = chroot.path().to_owned();
     nix_conf_path.push("etc");
     nix_conf_path.push("nix");
     nix_conf_path.push("nix.conf");
 
-    let mut config = match std::fs::OpenOptions::new()
-        .write(true)
-        .append(true)
-        .open(&nix_conf_path)
-    {
-        Ok(f) => f,
+    let mut merged = match std::fs::read_to_string(&nix_conf_path) {
+        Ok(c) => NixConf::parse(&c),
+        Err(e) if e.kind() == ErrorKind::NotFound => NixConf::new(),
         Err(e) => err!(
             "Unable to read Nix configuration file `{}`: {}",
             path_to_string(&nix_conf_path),
             e
         ),
     };
+    merged.merge(conf);
 
-    match writeln!(config, "sandbox = false\n") {
+    match std::fs::write(&nix_conf_path, merged.render()) {
         Ok(_) => Ok({}),
         Err(e) => err!(
             "Unable to update Nix configuration file `{}`: {}",
@@ -98,49 +235,350 @@ fn configure_nix(chroot: &Path) -> Result<()> {
     }
 }
 
-fn update_channels(chroot: &Path) -> Result<()> {
-    if let Err(e) = chroot::execute(
-        &chroot,
-        [
-            "nix-channel",
-            "--add",
-            "https://nixos.org/channels/nixpkgs-unstable",
-        ],
-    ) {
-        err!("Failed to subscribe to nixpkgs channel:\n{}", e);
+pub enum DefaultProfile {
+    Missing,
+    Valid(PathBuf),
+    /// Some distributions ship an empty directory, which keeps Nix from creating the profile.
+    RemovedEmptyDir,
+    MovedAside(PathBuf),
+    RemovedBrokenLink(PathBuf),
+}
+
+impl std::fmt::Display for DefaultProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Missing => write!(f, "default profile doesn't exist yet"),
+            Self::Valid(t) => write!(
+                f,
+                "default profile links to `{}` and was kept",
+                path_to_string(t)
+            ),
+            Self::RemovedEmptyDir => {
+                write!(f, "default profile was an empty directory and was removed")
+            }
+            Self::MovedAside(p) => write!(
+                f,
+                "default profile was a non-empty directory and was moved to `{}`",
+                path_to_string(p)
+            ),
+            Self::RemovedBrokenLink(t) => write!(
+                f,
+                "default profile linked to missing `{}` and was removed",
+                path_to_string(t)
+            ),
+        }
+    }
+}
+
+/// Idempotent, whatever a previous build using the same store left behind.
+pub fn init_default_profile(chroot: &Chroot) -> Result<DefaultProfile> {
+    let profile = chroot.path().join(DEFAULT_PROFILE.trim_start_matches('/'));
+
+    let metadata = match profile.symlink_metadata() {
+        Ok(m) => m,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(DefaultProfile::Missing),
+        Err(e) => err!(
+            "Unable to inspect default profile `{}`: {}",
+            path_to_string(&profile),
+            e
+        ),
+    };
+
+    if metadata.file_type().is_symlink() {
+        let target = match std::fs::read_link(&profile) {
+            Ok(t) => t,
+            Err(e) => err!("Unable to read `{}`: {}", path_to_string(&profile), e),
+        };
+        if resolve_in_chroot(chroot.path(), &profile).is_some_and(|p| p.is_dir()) {
+            return Ok(DefaultProfile::Valid(target));
+        }
+
+        return match std::fs::remove_file(&profile) {
+            Ok(_) => Ok(DefaultProfile::RemovedBrokenLink(target)),
+            Err(e) => err!(
+                "Unable to remove broken default profile `{}`: {}",
+                path_to_string(&profile),
+                e
+            ),
+        };
     }
 
-    let mut profile_dir = chroot.to_owned();
-    profile_dir.push("nix");
-    profile_dir.push("var");
-    profile_dir.push("nix");
-    profile_dir.push("profiles");
-    profile_dir.push("default");
-    if let Err(e) = std::fs::remove_dir(&profile_dir) {
+    if !metadata.is_dir() {
         err!(
-            "Failed to remove default profile directory `{}`: {}",
-            path_to_string(&profile_dir),
+            "Default profile `{}` is neither a link nor a directory",
+            path_to_string(&profile)
+        );
+    }
+
+    let empty = match std::fs::read_dir(&profile) {
+        Ok(mut d) => d.next().is_none(),
+        Err(e) => err!("Unable to read `{}`: {}", path_to_string(&profile), e),
+    };
+    if empty {
+        return match std::fs::remove_dir(&profile) {
+            Ok(_) => Ok(DefaultProfile::RemovedEmptyDir),
+            Err(e) => err!(
+                "Unable to remove default profile directory `{}`: {}",
+                path_to_string(&profile),
+                e
+            ),
+        };
+    }
+
+    // Its contents may be valuable, so they are kept for the user to look into
+    let aside = profile.with_extension("orig");
+    if aside.symlink_metadata().is_ok() {
+        err!(
+            "Default profile `{}` is a non-empty directory and can't be moved aside, `{}` already exists",
+            path_to_string(&profile),
+            path_to_string(&aside)
+        );
+    }
+    match std::fs::rename(&profile, &aside) {
+        Ok(_) => Ok(DefaultProfile::MovedAside(
+            PathBuf::from(DEFAULT_PROFILE).with_extension("orig"),
+        )),
+        Err(e) => err!(
+            "Unable to move default profile `{}` aside: {}",
+            path_to_string(&profile),
             e
-        )
+        ),
+    }
+}
+
+/// Absolute link targets are interpreted relative to `root`.
+fn resolve_in_chroot(root: &Path, path: &Path) -> Option<PathBuf> {
+    let mut path = path.to_owned();
+
+    // The same limit as Linux applies
+    for _ in 0..40 {
+        if !path.symlink_metadata().ok()?.file_type().is_symlink() {
+            return Some(path);
+        }
+
+        let target = std::fs::read_link(&path).ok()?;
+        path = match target.strip_prefix("/") {
+            Ok(t) => root.join(t),
+            Err(_) => path.parent()?.join(target),
+        };
+    }
+
+    None
+}
+
+fn update_channels(chroot: &Chroot, channel: &str) -> Result<()> {
+    let url = format!("https://nixos.org/channels/{}", channel);
+    if let Err(e) = chroot::execute(chroot, ["nix-channel", "--add", &url, "nixpkgs"]) {
+        err!("Failed to subscribe to `{}` channel:\n{}", channel, e);
     }
 
-    match chroot::execute(&chroot, ["nix-channel", "--update"]) {
+    match chroot::execute(chroot, ["nix-channel", "--update"]) {
         Ok(_) => Ok({}),
         Err(e) => err!("Failed to update Nix channels:\n{}", e),
     }
 }
 
-pub fn install_nixos_generators<R: AsRef<Path>>(chroot: R) -> Result<()> {
-    match chroot::execute(&chroot, ["nix-env", "-iA", "nixpkgs.nixos-generators"]) {
+pub enum GeneratorsFrom {
+    Channel,
+    PinnedNixpkgs,
+    Pinned,
+}
+
+pub fn install_nixos_generators(chroot: &Chroot, from: GeneratorsFrom) -> Result<()> {
+    let args = match from {
+        GeneratorsFrom::Channel => vec!["nix-env", "-iA", "nixpkgs.nixos-generators"],
+        GeneratorsFrom::PinnedNixpkgs => {
+            vec!["nix-env", "-f", PINNED_NIXPKGS, "-iA", "nixos-generators"]
+        }
+        GeneratorsFrom::Pinned => vec!["nix-env", "-f", PINNED_GENERATORS, "-i"],
+    };
+
+    match chroot::execute(chroot, args) {
         Ok(_) => Ok({}),
         Err(e) => err!("Failed to install `nixos-generators`:\n{}", e),
     }
 }
 
-// TODO: actually figure this out
-pub fn generate_lxc_image<P: AsRef<Path>>(chroot: P) -> Result<()> {
-    match chroot::execute(&chroot, ["nixos-generate", "-f", "lxc", "-c", "/lxc.nix"]) {
-        Ok(_) => Ok({}),
-        Err(e) => err!("Failed to install `nixos-generators`:\n{}", e),
+pub fn nixos_generators_version(chroot: &Chroot) -> Result<String> {
+    let installed = match chroot::output(chroot, ["nix-env", "-q", "nixos-generators"]) {
+        Ok(o) => o,
+        Err(e) => err!("Failed to query installed `nixos-generators`:\n{}", e),
+    };
+
+    let name = installed.lines().next().unwrap_or_default().trim();
+    match name.strip_prefix("nixos-generators-") {
+        Some(v) if !v.is_empty() => Ok(v.to_owned()),
+        _ if name.starts_with("nixos-generators") => Ok(UNKNOWN_VERSION.to_owned()),
+        _ => err!("`nixos-generators` isn't installed into the default profile"),
+    }
+}
+
+pub enum Generator {
+    Installed,
+    Flake(String),
+}
+
+impl Generator {
+    pub fn flake(commit: &str) -> Self {
+        Self::Flake(format!("github:nix-community/nixos-generators/{}", commit))
+    }
+
+    pub fn prepare(&self, chroot: &Chroot) -> Result<()> {
+        let reference = match self {
+            Self::Installed => return Ok({}),
+            Self::Flake(r) => format!("{}#nixos-generate", r),
+        };
+
+        match chroot::execute(chroot, ["nix", "build", "--no-link", &reference]) {
+            Ok(_) => Ok({}),
+            Err(e) => err!("Failed to build `{}`:\n{}", reference, e),
+        }
+    }
+
+    fn command(&self) -> Vec<String> {
+        match self {
+            Self::Installed => vec!["nixos-generate".to_owned()],
+            Self::Flake(r) => vec![
+                "nix".to_owned(),
+                "run".to_owned(),
+                format!("{}#nixos-generate", r),
+                "--".to_owned(),
+            ],
+        }
+    }
+}
+
+impl std::fmt::Display for Generator {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Installed => write!(f, "default profile"),
+            Self::Flake(r) => write!(f, "{}", r),
+        }
+    }
+}
+
+pub fn generate_lxc_image(chroot: &Chroot, generator: &Generator) -> Result<PathBuf> {
+    let mut args = generator.command();
+    args.extend(["-f", "lxc", "-c", nixos::CONFIGURATION, "-o", RESULT].map(String::from));
+
+    let output = match chroot::output(chroot, args) {
+        Ok(o) => o,
+        Err(e) => err!("Failed to generate LXC image:\n{}", e),
+    };
+
+    // `nixos-generate` prints the image itself, `RESULT` links to the directory containing it
+    let printed = output
+        .lines()
+        .rev()
+        .map(str::trim)
+        .find(|l| l.starts_with('/'));
+    match printed {
+        Some(p) => Ok(chroot.path().join(p.trim_start_matches('/'))),
+        None => resolve_result(chroot),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLAKE_LOCK: &str = r#"{
+  "nodes": {
+    "nixpkgs_2": {
+      "locked": { "rev": "0123456789abcdef0123456789abcdef01234567", "type": "github" }
+    },
+    "root": { "inputs": { "nixpkgs": "nixpkgs_2" } }
+  },
+  "root": "root",
+  "version": 7
+}"#;
+
+    #[test]
+    fn reads_locked_nixpkgs_revision() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("flake.lock"), FLAKE_LOCK).unwrap();
+
+        assert_eq!(
+            flake_nixpkgs_revision(dir.path()).ok().unwrap(),
+            "0123456789abcdef0123456789abcdef01234567"
+        );
+    }
+
+    #[test]
+    fn reports_unknown_revision_without_nixpkgs_input() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("flake.lock"),
+            r#"{ "nodes": { "root": {} }, "root": "root", "version": 7 }"#,
+        )
+        .unwrap();
+
+        assert_eq!(flake_nixpkgs_revision(dir.path()).ok().unwrap(), "unknown");
+        assert!(flake_nixpkgs_revision(&dir.path().join("missing")).is_err());
+    }
+
+    fn chroot() -> (tempfile::TempDir, Chroot, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let profiles = dir.path().join("nix/var/nix/profiles");
+        std::fs::create_dir_all(&profiles).unwrap();
+        let chroot = Chroot::new(dir.path());
+
+        (dir, chroot, profiles.join("default"))
+    }
+
+    #[test]
+    fn keeps_valid_default_profile() {
+        let (dir, chroot, profile) = chroot();
+        std::fs::create_dir_all(dir.path().join("nix/store/abc-profile")).unwrap();
+        std::os::unix::fs::symlink(
+            "/nix/store/abc-profile",
+            profile.with_file_name("default-1-link"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("default-1-link", &profile).unwrap();
+
+        let result = init_default_profile(&chroot).ok().unwrap();
+        assert!(matches!(result, DefaultProfile::Valid(t) if t == Path::new("default-1-link")));
+        assert!(profile.symlink_metadata().is_ok());
+    }
+
+    #[test]
+    fn removes_broken_links_and_empty_directories() {
+        let (_dir, chroot, profile) = chroot();
+        assert!(matches!(
+            init_default_profile(&chroot),
+            Ok(DefaultProfile::Missing)
+        ));
+
+        std::os::unix::fs::symlink("/nix/store/gone-profile", &profile).unwrap();
+        assert!(matches!(
+            init_default_profile(&chroot),
+            Ok(DefaultProfile::RemovedBrokenLink(_))
+        ));
+        assert!(profile.symlink_metadata().is_err());
+
+        std::fs::create_dir(&profile).unwrap();
+        assert!(matches!(
+            init_default_profile(&chroot),
+            Ok(DefaultProfile::RemovedEmptyDir)
+        ));
+        assert!(profile.symlink_metadata().is_err());
+    }
+
+    #[test]
+    fn moves_populated_directories_aside() {
+        let (_dir, chroot, profile) = chroot();
+        std::fs::create_dir_all(profile.join("bin")).unwrap();
+
+        assert!(matches!(
+            init_default_profile(&chroot),
+            Ok(DefaultProfile::MovedAside(p)) if p == Path::new("/nix/var/nix/profiles/default.orig")
+        ));
+        assert!(profile.with_extension("orig").join("bin").is_dir());
+
+        // Nothing is overwritten by a second attempt
+        std::fs::create_dir_all(profile.join("bin")).unwrap();
+        assert!(init_default_profile(&chroot).is_err());
+        assert!(profile.join("bin").is_dir());
     }
 }
@@ -0,0 +1,264 @@
+use crate::config::NixSetting;
+use std::collections::BTreeMap;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+macro_rules! err {
+    ($($args:expr),+) => {
+        return Err(Error::new(format!($($args),+)))
+    };
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Bool,
+    Sandbox,
+    Integer,
+    IntegerOrAuto,
+    String,
+    List,
+}
+
+const KNOWN_SETTINGS: [(&str, Kind); 25] = [
+    ("auto-optimise-store", Kind::Bool),
+    ("build-users-group", Kind::String),
+    ("connect-timeout", Kind::Integer),
+    ("cores", Kind::Integer),
+    ("download-attempts", Kind::Integer),
+    ("download-speed", Kind::Integer),
+    ("experimental-features", Kind::List),
+    ("extra-experimental-features", Kind::List),
+    ("extra-substituters", Kind::List),
+    ("extra-trusted-public-keys", Kind::List),
+    ("fallback", Kind::Bool),
+    ("http-connections", Kind::Integer),
+    ("keep-derivations", Kind::Bool),
+    ("keep-outputs", Kind::Bool),
+    ("max-free", Kind::Integer),
+    ("max-jobs", Kind::IntegerOrAuto),
+    ("min-free", Kind::Integer),
+    ("narinfo-cache-negative-ttl", Kind::Integer),
+    ("netrc-file", Kind::String),
+    ("require-sigs", Kind::Bool),
+    ("sandbox", Kind::Sandbox),
+    ("substituters", Kind::List),
+    ("trusted-public-keys", Kind::List),
+    ("trusted-substituters", Kind::List),
+    ("trusted-users", Kind::List),
+];
+
+/// Settings are kept by name and rendered in a stable order, so rewriting is idempotent.
+#[derive(Default)]
+pub struct NixConf {
+    settings: BTreeMap<String, String>,
+    /// Rendered before the settings, so that the settings take precedence.
+    includes: Vec<String>,
+}
+
+impl NixConf {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse(content: &str) -> Self {
+        let mut conf = Self::new();
+
+        for line in content.lines().map(strip_comment).map(str::trim) {
+            let directive = line.split_whitespace().next().unwrap_or_default();
+            if directive == "include" || directive == "!include" {
+                conf.include(line);
+            } else if let Some((k, v)) = line.split_once('=') {
+                if !k.trim().is_empty() {
+                    conf.set(k.trim(), v.trim());
+                }
+            }
+        }
+
+        conf
+    }
+
+    pub fn set<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        self.settings.insert(key.into(), value.into());
+    }
+
+    pub fn require<K: Into<String>>(&mut self, key: K, values: &[&str]) {
+        let value = self.settings.entry(key.into()).or_default();
+        for v in values {
+            if !value.split_whitespace().any(|e| e == *v) {
+                if !value.is_empty() {
+                    value.push(' ');
+                }
+                value.push_str(v);
+            }
+        }
+    }
+
+    pub fn set_user(&mut self, settings: &BTreeMap<String, NixSetting>) -> Result<()> {
+        for (key, value) in settings {
+            let kind = match KNOWN_SETTINGS.iter().find(|(k, _)| k == key) {
+                Some((_, kind)) => *kind,
+                None => err!("`{}` is not a known `nix.conf` setting", key),
+            };
+
+            match render_value(kind, value) {
+                Some(v) => self.set(key, v),
+                None => err!("`{}` of `nix.conf` has a value of the wrong type", key),
+            }
+        }
+
+        Ok({})
+    }
+
+    pub fn merge(&mut self, other: &NixConf) {
+        for (key, value) in &other.settings {
+            self.set(key, value);
+        }
+        for include in &other.includes {
+            self.include(include);
+        }
+    }
+
+    fn include(&mut self, directive: &str) {
+        if !self.includes.iter().any(|i| i == directive) {
+            self.includes.push(directive.to_owned());
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut content = String::from("# Generated by nixops-rs, changes will be overwritten\n");
+        for include in &self.includes {
+            content.push_str(&format!("{}\n", include));
+        }
+        for (key, value) in &self.settings {
+            content.push_str(&format!("{} = {}\n", key, value));
+        }
+
+        content
+    }
+}
+
+pub struct Error {
+    error: String,
+}
+
+impl Error {
+    pub fn new<S: AsRef<str>>(error: S) -> Self {
+        let error = error.as_ref().to_owned();
+
+        Self { error }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+/// `#` only starts a comment at the beginning of the line or after whitespace.
+fn strip_comment(line: &str) -> &str {
+    let mut previous = None;
+    for (i, c) in line.char_indices() {
+        if c == '#' && previous.is_none_or(char::is_whitespace) {
+            return &line[..i];
+        }
+        previous = Some(c);
+    }
+
+    line
+}
+
+fn render_value(kind: Kind, value: &NixSetting) -> Option<String> {
+    Some(match (kind, value) {
+        (Kind::Bool | Kind::Sandbox, NixSetting::Bool(b)) => b.to_string(),
+        (Kind::Sandbox, NixSetting::String(s)) if s == "relaxed" => s.clone(),
+        (Kind::Integer | Kind::IntegerOrAuto, NixSetting::Integer(i)) => i.to_string(),
+        (Kind::IntegerOrAuto, NixSetting::String(s)) if s == "auto" => s.clone(),
+        (Kind::String, NixSetting::String(s)) if !s.contains('\n') => s.clone(),
+        (Kind::List, NixSetting::String(s)) if !s.contains('\n') => s.clone(),
+        (Kind::List, NixSetting::List(l)) if l.iter().all(|v| !v.contains(char::is_whitespace)) => {
+            l.join(" ")
+        }
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_settings_and_comments() {
+        let conf = NixConf::parse(
+            "# distribution defaults\n\
+             build-users-group = nixbld # comment\n\
+             substituters = https://cache.example.org/#main https://cache.nixos.org\n\
+             \n\
+             malformed line\n\
+             =value\n",
+        );
+
+        assert_eq!(
+            conf.render(),
+            "# Generated by nixops-rs, changes will be overwritten\n\
+             build-users-group = nixbld\n\
+             substituters = https://cache.example.org/#main https://cache.nixos.org\n"
+        );
+    }
+
+    #[test]
+    fn keeps_includes() {
+        let mut conf = NixConf::parse("include /etc/nix/extra.conf\nsandbox = true\n");
+        conf.merge(&NixConf::parse(
+            "!include /etc/nix/optional.conf\ninclude /etc/nix/extra.conf\nsandbox = false\n",
+        ));
+
+        assert_eq!(
+            conf.render(),
+            "# Generated by nixops-rs, changes will be overwritten\n\
+             include /etc/nix/extra.conf\n\
+             !include /etc/nix/optional.conf\n\
+             sandbox = false\n"
+        );
+    }
+
+    #[test]
+    fn requires_list_values_once() {
+        let mut conf = NixConf::new();
+        conf.set("experimental-features", "nix-command");
+        conf.require("experimental-features", &["nix-command", "flakes"]);
+        conf.require("trusted-users", &["root"]);
+
+        assert!(conf
+            .render()
+            .ends_with("experimental-features = nix-command flakes\ntrusted-users = root\n"));
+    }
+
+    #[test]
+    fn checks_user_settings() {
+        let settings =
+            |yaml: &str| -> BTreeMap<String, NixSetting> { serde_yaml::from_str(yaml).unwrap() };
+        let mut conf = NixConf::new();
+
+        assert!(conf
+            .set_user(&settings(
+                "max-jobs: auto\ncores: 4\nsandbox: relaxed\nsubstituters: [a, b]"
+            ))
+            .is_ok());
+        assert!(conf.render().contains("max-jobs = auto\n"));
+        assert!(conf.render().contains("substituters = a b\n"));
+
+        let e = conf
+            .set_user(&settings("unknown-setting: 1"))
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.to_string(),
+            "`unknown-setting` is not a known `nix.conf` setting"
+        );
+        assert!(conf.set_user(&settings("cores: many")).is_err());
+        assert!(conf
+            .set_user(&settings("trusted-users: [\"a b\"]"))
+            .is_err());
+    }
+}
@@ -0,0 +1,513 @@
+use crate::chroot::Chroot;
+use crate::config::{Container, Networking, User};
+use crate::fs::path_to_string;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+macro_rules! err {
+    ($($args:expr),+) => {
+        return Err(Error::new(format!($($args),+)))
+    };
+}
+
+pub const CONFIGURATION: &str = "/lxc.nix";
+
+const IMPORTS: &str = "/opt/lxc-imports";
+
+const DEFAULT_MODULE: &str = "default.nix";
+
+/// Strings are escaped, so that no value can inject Nix code.
+pub fn render(container: &Container) -> Result<String> {
+    let mut module = String::from("# Generated by nixops-rs, changes will be overwritten\n");
+    module.push_str("{ pkgs, ... }:\n\n{\n");
+
+    module.push_str("  imports = [\n");
+    for (i, import) in container.imports.iter().enumerate() {
+        module.push_str(&format!("    {}\n", import_path(i, import)));
+    }
+    module.push_str("  ];\n");
+
+    if let Some(hostname) = &container.hostname {
+        if !is_hostname(hostname) {
+            err!("`{}` is not a valid hostname", hostname);
+        }
+        module.push_str(&format!("  networking.hostName = {};\n", string(hostname)));
+    }
+    if let Some(timezone) = &container.timezone {
+        if timezone.is_empty() || timezone.contains(char::is_whitespace) {
+            err!("`{}` is not a valid time zone", timezone);
+        }
+        module.push_str(&format!("  time.timeZone = {};\n", string(timezone)));
+    }
+    if let Some(locale) = &container.locale {
+        if locale.is_empty() || locale.contains(char::is_whitespace) {
+            err!("`{}` is not a valid locale", locale);
+        }
+        module.push_str(&format!("  i18n.defaultLocale = {};\n", string(locale)));
+    }
+
+    for user in &container.users {
+        render_user(&mut module, user)?;
+    }
+
+    for service in &container.services {
+        if !is_attribute_path(service) {
+            err!("`{}` is not a valid service name", service);
+        }
+        module.push_str(&format!("  services.{}.enable = true;\n", service));
+    }
+
+    if !container.packages.is_empty() {
+        module.push_str("  environment.systemPackages = with pkgs; [\n");
+        for package in &container.packages {
+            if !is_attribute_path(package) {
+                err!("`{}` is not a valid package attribute", package);
+            }
+            module.push_str(&format!("    {}\n", package));
+        }
+        module.push_str("  ];\n");
+    }
+
+    render_networking(&mut module, &container.networking)?;
+
+    module.push_str("}\n");
+
+    Ok(module)
+}
+
+/// Modules importing their siblings have to be a directory, which is copied without `skip`.
+pub fn install(chroot: &Chroot, container: &Container, skip: &Path) -> Result<()> {
+    let module = render(container)?;
+
+    remove_imports(chroot)?;
+    let imports = chroot.path().join(IMPORTS.trim_start_matches('/'));
+    if let Err(e) = std::fs::create_dir_all(&imports) {
+        err!("Unable to create `{}`: {}", path_to_string(&imports), e);
+    }
+    let skip = skip.canonicalize().unwrap_or_else(|_| skip.to_owned());
+    for (i, import) in container.imports.iter().enumerate() {
+        let target = chroot
+            .path()
+            .join(import_path(i, import).trim_start_matches('/'));
+        let result = match import.is_dir() {
+            true => copy_module_dir(import, &target, &skip),
+            false => std::fs::copy(import, &target).map(|_| {}),
+        };
+        if let Err(e) = result {
+            err!(
+                "Unable to copy `{}` into `{}`: {}",
+                path_to_string(import),
+                path_to_string(&target),
+                e
+            );
+        }
+    }
+
+    let path = chroot.path().join(CONFIGURATION.trim_start_matches('/'));
+    match std::fs::write(&path, module) {
+        Ok(_) => Ok({}),
+        Err(e) => err!("Unable to write `{}`: {}", path_to_string(&path), e),
+    }
+}
+
+pub fn remove_imports(chroot: &Chroot) -> Result<()> {
+    let imports = chroot.path().join(IMPORTS.trim_start_matches('/'));
+    match std::fs::remove_dir_all(&imports) {
+        Ok(_) => Ok({}),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok({}),
+        Err(e) => err!("Unable to remove `{}`: {}", path_to_string(&imports), e),
+    }
+}
+
+pub fn check_imports(container: &Container) -> Result<()> {
+    for import in &container.imports {
+        if import.is_dir() {
+            if !import.join(DEFAULT_MODULE).is_file() {
+                err!(
+                    "NixOS module directory `{}` has no `{}`",
+                    path_to_string(import),
+                    DEFAULT_MODULE
+                );
+            }
+        } else if !import.is_file() {
+            err!("NixOS module `{}` doesn't exist", path_to_string(import));
+        }
+    }
+
+    Ok({})
+}
+
+pub fn import_locations(container: &Container) -> Vec<(String, PathBuf)> {
+    container
+        .imports
+        .iter()
+        .enumerate()
+        .map(|(i, import)| (import_path(i, import), import.clone()))
+        .collect()
+}
+
+pub struct Error {
+    error: String,
+}
+
+impl Error {
+    pub fn new<S: AsRef<str>>(error: S) -> Self {
+        let error = error.as_ref().to_owned();
+
+        Self { error }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+fn import_path(index: usize, import: &Path) -> String {
+    match import.is_dir() {
+        true => format!("{}/{}", IMPORTS, index),
+        false => format!("{}/{}.nix", IMPORTS, index),
+    }
+}
+
+fn copy_module_dir(source: &Path, target: &Path, skip: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(target)?;
+
+    for entry in source.read_dir()? {
+        let entry = entry?;
+        let (path, target) = (entry.path(), target.join(entry.file_name()));
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            if path.canonicalize()? != skip {
+                copy_module_dir(&path, &target, skip)?;
+            }
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(std::fs::read_link(&path)?, &target)?;
+        } else if file_type.is_file() {
+            std::fs::copy(&path, &target)?;
+        }
+    }
+
+    Ok({})
+}
+
+fn render_user(module: &mut String, user: &User) -> Result<()> {
+    if !is_user_name(&user.name) {
+        err!("`{}` is not a valid user name", user.name);
+    }
+    let prefix = format!("  users.users.{}", string(&user.name));
+
+    // The superuser exists already
+    if user.name != "root" {
+        module.push_str(&format!("{}.isNormalUser = true;\n", prefix));
+    }
+    if !user.groups.is_empty() {
+        for group in &user.groups {
+            if !is_user_name(group) {
+                err!("`{}` is not a valid group name", group);
+            }
+        }
+        module.push_str(&format!(
+            "{}.extraGroups = {};\n",
+            prefix,
+            list(&user.groups)
+        ));
+    }
+    if !user.ssh_keys.is_empty() {
+        module.push_str(&format!(
+            "{}.openssh.authorizedKeys.keys = {};\n",
+            prefix,
+            list(&user.ssh_keys)
+        ));
+    }
+
+    Ok({})
+}
+
+fn render_networking(module: &mut String, networking: &Networking) -> Result<()> {
+    if !is_interface_name(&networking.interface) {
+        err!("`{}` is not a valid interface name", networking.interface);
+    }
+
+    let address = match &networking.address {
+        Some(a) => a,
+        None => {
+            module.push_str("  networking.useDHCP = true;\n");
+            if networking.gateway.is_some() {
+                err!("A gateway can only be configured along with a static address");
+            }
+
+            return render_nameservers(module, &networking.nameservers);
+        }
+    };
+
+    let (ip, prefix) = match parse_address(address) {
+        Some(a) => a,
+        None => err!(
+            "`{}` is not a valid IPv4 address with prefix length, e.g. `192.168.1.10/24`",
+            address
+        ),
+    };
+
+    module.push_str("  networking.useDHCP = false;\n");
+    module.push_str(&format!(
+        "  networking.interfaces.{}.ipv4.addresses = [ {{ address = {}; prefixLength = {}; }} ];\n",
+        string(&networking.interface),
+        string(&ip.to_string()),
+        prefix
+    ));
+    if let Some(gateway) = &networking.gateway {
+        if gateway.parse::<Ipv4Addr>().is_err() {
+            err!("`{}` is not a valid IPv4 gateway", gateway);
+        }
+        module.push_str(&format!(
+            "  networking.defaultGateway = {};\n",
+            string(gateway)
+        ));
+    }
+
+    render_nameservers(module, &networking.nameservers)
+}
+
+fn render_nameservers(module: &mut String, nameservers: &[String]) -> Result<()> {
+    if nameservers.is_empty() {
+        return Ok({});
+    }
+
+    for nameserver in nameservers {
+        if nameserver.parse::<IpAddr>().is_err() {
+            err!("`{}` is not a valid nameserver address", nameserver);
+        }
+    }
+    module.push_str(&format!(
+        "  networking.nameservers = {};\n",
+        list(nameservers)
+    ));
+
+    Ok({})
+}
+
+fn parse_address(address: &str) -> Option<(Ipv4Addr, u8)> {
+    let (ip, prefix) = address.split_once('/')?;
+    let prefix = prefix.parse().ok().filter(|p| *p <= 32)?;
+
+    Some((ip.parse().ok()?, prefix))
+}
+
+fn string(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+        .replace('\t', "\\t");
+
+    format!("\"{}\"", escaped)
+}
+
+fn list(values: &[String]) -> String {
+    let items: Vec<String> = values.iter().map(|v| string(v)).collect();
+
+    format!("[ {} ]", items.join(" "))
+}
+
+/// A single RFC 1123 label, which is all `networking.hostName` accepts.
+fn is_hostname(name: &str) -> bool {
+    (1..=63).contains(&name.len())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+}
+
+fn is_user_name(name: &str) -> bool {
+    (1..=32).contains(&name.len())
+        && name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+fn is_interface_name(name: &str) -> bool {
+    (1..=15).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+fn is_attribute_path(path: &str) -> bool {
+    path.split('.').all(|identifier| {
+        identifier.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && identifier
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '\'')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str) -> User {
+        User {
+            name: name.to_owned(),
+            groups: vec!["wheel".to_owned()],
+            ssh_keys: vec!["ssh-ed25519 AAAA \"${x}\"".to_owned()],
+        }
+    }
+
+    #[test]
+    fn renders_container() {
+        let container = Container {
+            hostname: Some("web-1".to_owned()),
+            users: vec![user("alice")],
+            services: vec!["openssh".to_owned()],
+            packages: vec!["python3Packages.requests".to_owned()],
+            networking: Networking {
+                address: Some("192.168.1.10/24".to_owned()),
+                gateway: Some("192.168.1.1".to_owned()),
+                nameservers: vec!["1.1.1.1".to_owned()],
+                ..Networking::default()
+            },
+            ..Container::default()
+        };
+
+        let module = render(&container).ok().unwrap();
+        assert!(module.contains("  networking.hostName = \"web-1\";\n"));
+        assert!(module.contains("  users.users.\"alice\".isNormalUser = true;\n"));
+        assert!(module.contains("keys = [ \"ssh-ed25519 AAAA \\\"\\${x}\\\"\" ];\n"));
+        assert!(module.contains("  services.openssh.enable = true;\n"));
+        assert!(module.contains("    python3Packages.requests\n"));
+        assert!(module.contains("address = \"192.168.1.10\"; prefixLength = 24;"));
+        assert!(module.contains("  networking.defaultGateway = \"192.168.1.1\";\n"));
+        assert!(module.contains("  networking.nameservers = [ \"1.1.1.1\" ];\n"));
+        assert!(module.ends_with("}\n"));
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let invalid = [
+            Container {
+                hostname: Some("-web".to_owned()),
+                ..Container::default()
+            },
+            Container {
+                users: vec![user("Alice")],
+                ..Container::default()
+            },
+            Container {
+                services: vec!["openssh; x".to_owned()],
+                ..Container::default()
+            },
+            Container {
+                networking: Networking {
+                    gateway: Some("192.168.1.1".to_owned()),
+                    ..Networking::default()
+                },
+                ..Container::default()
+            },
+        ];
+
+        for container in &invalid {
+            assert!(render(container).is_err());
+        }
+    }
+
+    #[test]
+    fn validates_names() {
+        assert!(is_hostname("web-1"));
+        assert!(!is_hostname("web.example.com"));
+        assert!(!is_hostname(&"a".repeat(64)));
+        assert!(is_user_name("_build-1"));
+        assert!(!is_user_name("1user"));
+        assert!(is_interface_name("eth0.10"));
+        assert!(!is_interface_name("a-very-long-interface"));
+        assert!(is_attribute_path("haskellPackages.lens'"));
+        assert!(!is_attribute_path("pkgs..hello"));
+        assert!(!is_attribute_path("(import ./x.nix)"));
+    }
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(
+            parse_address("10.0.0.2/8"),
+            Some((Ipv4Addr::new(10, 0, 0, 2), 8))
+        );
+        assert_eq!(parse_address("10.0.0.2/33"), None);
+        assert_eq!(parse_address("10.0.0.2"), None);
+        assert_eq!(parse_address("::1/64"), None);
+    }
+
+    #[test]
+    fn installs_imported_modules() {
+        let host = tempfile::tempdir().unwrap();
+        let file = host.path().join("web.nix");
+        std::fs::write(&file, "{ }").unwrap();
+        std::fs::write(host.path().join("secret"), "").unwrap();
+        let dir = host.path().join("dir");
+        std::fs::create_dir_all(dir.join("workdir/rootfs")).unwrap();
+        std::fs::write(dir.join(DEFAULT_MODULE), "{ imports = [ ./common.nix ]; }").unwrap();
+        std::fs::write(dir.join("common.nix"), "{ }").unwrap();
+
+        let container = Container {
+            imports: vec![file.clone(), dir.clone()],
+            ..Container::default()
+        };
+        check_imports(&container).ok().unwrap();
+
+        let module = render(&container).ok().unwrap();
+        assert!(module.contains("    /opt/lxc-imports/0.nix\n"));
+        assert!(module.contains("    /opt/lxc-imports/1\n"));
+
+        let root = tempfile::tempdir().unwrap();
+        let chroot = Chroot::new(root.path());
+        let imports = root.path().join("opt/lxc-imports");
+        std::fs::create_dir_all(imports.join("2")).unwrap();
+        install(&chroot, &container, &dir.join("workdir"))
+            .ok()
+            .unwrap();
+        assert!(imports.join("0.nix").is_file());
+        assert!(!imports.join("secret").exists());
+        assert!(imports.join("1/common.nix").is_file());
+        assert!(!imports.join("1/workdir").exists());
+        assert!(!imports.join("2").exists());
+        assert!(root.path().join("lxc.nix").is_file());
+
+        assert_eq!(
+            import_locations(&container),
+            vec![
+                ("/opt/lxc-imports/0.nix".to_owned(), file),
+                ("/opt/lxc-imports/1".to_owned(), dir),
+            ]
+        );
+
+        remove_imports(&chroot).ok().unwrap();
+        assert!(!imports.exists());
+        remove_imports(&chroot).ok().unwrap();
+    }
+
+    #[test]
+    fn rejects_missing_modules() {
+        let host = tempfile::tempdir().unwrap();
+        let missing = Container {
+            imports: vec![host.path().join("missing.nix")],
+            ..Container::default()
+        };
+        let without_default = Container {
+            imports: vec![host.path().to_owned()],
+            ..Container::default()
+        };
+
+        assert!(check_imports(&missing).is_err());
+        assert!(check_imports(&without_default)
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("has no `default.nix`"));
+    }
+}
@@ -0,0 +1,117 @@
+use crate::config::{Preflight, Size};
+use crate::fs::{fs_stats, path_to_string};
+use std::path::Path;
+
+pub struct Finding {
+    pub passed: bool,
+    pub message: String,
+}
+
+impl Finding {
+    fn pass<S: Into<String>>(message: S) -> Self {
+        Self {
+            passed: true,
+            message: message.into(),
+        }
+    }
+
+    fn fail<S: Into<String>>(message: S) -> Self {
+        Self {
+            passed: false,
+            message: message.into(),
+        }
+    }
+}
+
+pub fn check_work_dir<P: AsRef<Path>>(work_dir: P, requirements: &Preflight) -> Vec<Finding> {
+    let work_dir = work_dir.as_ref();
+    let stats = match fs_stats(work_dir) {
+        Ok(s) => s,
+        Err(e) => return vec![Finding::fail(format!("{}", e))],
+    };
+
+    let mut findings = vec![];
+
+    let free_space = Size(stats.free_bytes);
+    if stats.free_bytes >= requirements.min_free_space.0 {
+        findings.push(Finding::pass(format!(
+            "{} of free space (at least {} required)",
+            free_space, requirements.min_free_space
+        )));
+    } else {
+        findings.push(Finding::fail(format!(
+            "only {} of free space on the filesystem of `{}`, but at least {} is required",
+            free_space,
+            path_to_string(work_dir),
+            requirements.min_free_space
+        )));
+    }
+
+    if !stats.has_inode_limit {
+        findings.push(Finding::pass("filesystem has no fixed inode limit"));
+    } else if stats.free_inodes >= requirements.min_free_inodes {
+        findings.push(Finding::pass(format!(
+            "{} free inodes (at least {} required)",
+            stats.free_inodes, requirements.min_free_inodes
+        )));
+    } else {
+        findings.push(Finding::fail(format!(
+            "only {} free inodes on the filesystem of `{}`, but at least {} are required",
+            stats.free_inodes,
+            path_to_string(work_dir),
+            requirements.min_free_inodes
+        )));
+    }
+
+    if stats.noexec {
+        findings.push(Finding::fail(format!(
+            "`{}` is on a filesystem mounted with `noexec`, programs in the chroot can't run",
+            path_to_string(work_dir)
+        )));
+    } else {
+        findings.push(Finding::pass("filesystem allows execution"));
+    }
+
+    if stats.nodev {
+        findings.push(Finding::fail(format!(
+            "`{}` is on a filesystem mounted with `nodev`, device nodes in the chroot won't work",
+            path_to_string(work_dir)
+        )));
+    } else {
+        findings.push(Finding::pass("filesystem allows device nodes"));
+    }
+
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_free_space() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut requirements = Preflight {
+            min_free_space: Size(0),
+            min_free_inodes: 0,
+        };
+
+        let findings = check_work_dir(dir.path(), &requirements);
+        assert_eq!(findings.len(), 4);
+        assert!(findings[0].passed && findings[1].passed);
+
+        requirements.min_free_space = Size(u64::MAX);
+        let findings = check_work_dir(dir.path(), &requirements);
+        assert!(!findings[0].passed);
+        assert!(findings[0].message.starts_with("only "));
+    }
+
+    #[test]
+    fn reports_missing_work_dir() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let findings = check_work_dir(dir.path().join("missing"), &Preflight::default());
+        assert_eq!(findings.len(), 1);
+        assert!(!findings[0].passed);
+    }
+}
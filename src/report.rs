@@ -0,0 +1,63 @@
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Serialize)]
+pub struct Report {
+    pub started: String,
+    pub architecture: String,
+    pub base_system: String,
+    pub nixpkgs: Nixpkgs,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flake: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generators: Option<Generators>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Nixpkgs {
+    pub source: String,
+    /// `unknown` if nixpkgs doesn't record one, as tarballs often don't.
+    pub revision: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Generators {
+    pub source: String,
+    pub version: String,
+}
+
+impl Report {
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let content = match serde_yaml::to_string(self) {
+            Ok(c) => c,
+            Err(e) => return Err(std::io::Error::other(e)),
+        };
+
+        std::fs::write(path, content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nixpkgs(sha256: Option<&str>) -> String {
+        let nixpkgs = Nixpkgs {
+            source: "https://example.org/nixpkgs.tar.gz".to_owned(),
+            revision: "unknown".to_owned(),
+            sha256: sha256.map(str::to_owned),
+        };
+
+        serde_yaml::to_string(&nixpkgs).unwrap()
+    }
+
+    #[test]
+    fn records_pinned_tarballs() {
+        assert!(nixpkgs(Some("0123abcd")).contains("sha256: 0123abcd"));
+        assert!(!nixpkgs(None).contains("sha256"));
+    }
+}
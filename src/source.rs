@@ -0,0 +1,303 @@
+use crate::{fs::path_to_string, http};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+macro_rules! err {
+    ($($args:expr),+) => {
+        return Err(Error::new(format!($($args),+)))
+    };
+}
+
+#[derive(Clone)]
+pub enum Source {
+    Https(Url),
+    Http(Url),
+    File(PathBuf),
+}
+
+impl Source {
+    pub fn parse(location: &str) -> Result<Self> {
+        let url = match Url::parse(location) {
+            Ok(u) => u,
+            Err(e) => err!("`{}` is not a valid URL: {}", location, e),
+        };
+
+        Ok(match url.scheme() {
+            "https" => Self::Https(url),
+            "http" => Self::Http(url),
+            "file" => match url.to_file_path() {
+                Ok(p) => Self::File(p),
+                Err(_) => err!("`{}` is not a valid local file URL", location),
+            },
+            s => err!(
+                "`{}` uses unsupported `{}` scheme, expected `https`, `http` or `file`",
+                location,
+                s
+            ),
+        })
+    }
+
+    pub fn join(&self, path: &str) -> Result<Self> {
+        let path = path.trim_start_matches('/');
+
+        Ok(match self {
+            Self::Https(u) => Self::Https(join_url(u, path)?),
+            Self::Http(u) => Self::Http(join_url(u, path)?),
+            Self::File(p) => Self::File(p.join(path)),
+        })
+    }
+
+    pub fn is_file(&self) -> bool {
+        matches!(self, Self::File(_))
+    }
+
+    /// Refuses plain HTTP unless it is allowed and whatever is downloaded has a pinned checksum.
+    pub fn check_allowed(&self, allow_http: bool, pinned_checksum: bool) -> Result<()> {
+        if !matches!(self, Self::Http(_)) {
+            return Ok({});
+        }
+
+        if !allow_http {
+            err!(
+                "`{}` uses plain HTTP, which requires `http.allow_http` to be enabled",
+                self
+            );
+        }
+
+        if !pinned_checksum {
+            err!(
+                "`{}` uses plain HTTP, which requires the checksum to be pinned in the configuration",
+                self
+            );
+        }
+
+        Ok({})
+    }
+
+    pub fn read_to_string(&self, client: &http::Client) -> Result<String> {
+        match self {
+            Self::Https(u) | Self::Http(u) => Ok(fetch(client, u)?.into_text()?),
+            Self::File(p) => match std::fs::read_to_string(p) {
+                Ok(c) => Ok(c),
+                Err(e) => err!("Unable to read `{}`: {}", path_to_string(p), e),
+            },
+        }
+    }
+
+    /// Revalidates documents cached in `cache_dir` with conditional requests.
+    pub fn read_to_string_cached(&self, client: &http::Client, cache_dir: &Path) -> Result<String> {
+        let url = match self {
+            Self::Https(u) | Self::Http(u) => u,
+            Self::File(_) => return self.read_to_string(client),
+        };
+
+        let cache_path = cache_dir.join(format!(
+            "{:x}.yaml",
+            Sha256::digest(url.as_str().as_bytes())
+        ));
+        let cached = read_cache_entry(&cache_path).filter(|e| e.url == url.as_str());
+
+        let mut req = http::GetRequest::new(url.clone())?;
+        if let Some(entry) = &cached {
+            if let Some(etag) = &entry.etag {
+                req = req.if_none_match(etag);
+            }
+            if let Some(date) = &entry.last_modified {
+                req = req.if_modified_since(date);
+            }
+        }
+
+        let resp = client.get(req)?;
+        if resp.is_not_modified() {
+            return match cached {
+                Some(e) => Ok(e.body),
+                None => err!("`{}` is reported unchanged, but isn't cached", url),
+            };
+        }
+
+        let entry = CacheEntry {
+            url: url.to_string(),
+            etag: resp.etag(),
+            last_modified: resp.last_modified(),
+            body: resp.into_text()?,
+        };
+        if entry.etag.is_some() || entry.last_modified.is_some() {
+            write_cache_entry(cache_dir, &cache_path, &entry)?;
+        }
+
+        Ok(entry.body)
+    }
+
+    pub fn open(&self, client: &http::Client) -> Result<(Box<dyn Read + Send>, Option<u64>)> {
+        match self {
+            Self::Https(u) | Self::Http(u) => {
+                let resp = fetch(client, u)?;
+                let size = resp.content_length();
+
+                Ok((resp.into_reader()?, size))
+            }
+            Self::File(p) => match File::open(p).and_then(|f| Ok((f.metadata()?.len(), f))) {
+                Ok((size, f)) => Ok((Box::new(f), Some(size))),
+                Err(e) => err!("Unable to open `{}`: {}", path_to_string(p), e),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Https(u) | Self::Http(u) => write!(f, "{}", u),
+            Self::File(p) => write!(f, "file://{}", path_to_string(p)),
+        }
+    }
+}
+
+pub struct Error {
+    error: String,
+}
+
+impl Error {
+    pub fn new<S: AsRef<str>>(error: S) -> Self {
+        let error = error.as_ref().to_owned();
+
+        Self { error }
+    }
+}
+
+impl From<http::Error> for Error {
+    fn from(e: http::Error) -> Self {
+        Error::new(format!("{}", e))
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+fn join_url(base: &Url, path: &str) -> Result<Url> {
+    let mut base = base.clone();
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+
+    match base.join(path) {
+        Ok(u) => Ok(u),
+        Err(e) => err!("Unable to append `{}` to `{}`: {}", path, base, e),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    body: String,
+}
+
+fn read_cache_entry(path: &Path) -> Option<CacheEntry> {
+    // A missing or unreadable entry only means the document is downloaded unconditionally
+    let content = std::fs::read_to_string(path).ok()?;
+
+    serde_yaml::from_str(&content).ok()
+}
+
+fn write_cache_entry(dir: &Path, path: &Path, entry: &CacheEntry) -> Result<()> {
+    let content = match serde_yaml::to_string(entry) {
+        Ok(c) => c,
+        Err(e) => err!("Unable to serialize cache entry of `{}`: {}", entry.url, e),
+    };
+
+    match std::fs::create_dir_all(dir).and_then(|_| std::fs::write(path, content)) {
+        Ok(_) => Ok({}),
+        Err(e) => err!("Unable to write `{}`: {}", path_to_string(path), e),
+    }
+}
+
+fn fetch(client: &http::Client, url: &Url) -> Result<http::Response> {
+    let req = http::GetRequest::new(url.clone())?;
+
+    Ok(client.get(req)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_supported_schemes() {
+        assert!(matches!(
+            Source::parse("https://example.org/a"),
+            Ok(Source::Https(_))
+        ));
+        assert!(matches!(
+            Source::parse("http://example.org/a"),
+            Ok(Source::Http(_))
+        ));
+        match Source::parse("file:///srv/mirror") {
+            Ok(Source::File(p)) => assert_eq!(p, PathBuf::from("/srv/mirror")),
+            _ => panic!("expected a local file"),
+        }
+        assert!(Source::parse("ftp://example.org/a").is_err());
+        assert!(Source::parse("/srv/mirror").is_err());
+    }
+
+    #[test]
+    fn joins_paths_as_directories() {
+        let https = Source::parse("https://example.org/alpine").ok().unwrap();
+        assert_eq!(
+            https
+                .join("/v3.19/latest-releases.yaml")
+                .ok()
+                .unwrap()
+                .to_string(),
+            "https://example.org/alpine/v3.19/latest-releases.yaml"
+        );
+
+        let file = Source::parse("file:///srv/mirror/").ok().unwrap();
+        assert_eq!(
+            file.join("v3.19/rootfs.tar.gz").ok().unwrap().to_string(),
+            "file:///srv/mirror/v3.19/rootfs.tar.gz"
+        );
+    }
+
+    #[test]
+    fn allows_plain_http_only_with_pinned_checksums() {
+        let http = Source::parse("http://example.org/a").ok().unwrap();
+        assert!(http.check_allowed(true, true).is_ok());
+        assert!(http.check_allowed(false, true).is_err());
+        assert!(http.check_allowed(true, false).is_err());
+
+        let https = Source::parse("https://example.org/a").ok().unwrap();
+        assert!(https.check_allowed(false, false).is_ok());
+    }
+
+    #[test]
+    fn reads_local_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("release.yaml"), "version: 1\n").unwrap();
+        let client = http::Client::builder().build().ok().unwrap();
+        let source = Source::File(dir.path().to_owned())
+            .join("release.yaml")
+            .ok()
+            .unwrap();
+
+        assert_eq!(
+            source
+                .read_to_string_cached(&client, &dir.path().join("cache"))
+                .ok()
+                .unwrap(),
+            "version: 1\n"
+        );
+        assert_eq!(source.open(&client).ok().unwrap().1, Some(11));
+        assert!(!dir.path().join("cache").exists());
+    }
+}
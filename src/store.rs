@@ -0,0 +1,242 @@
+use crate::chroot::{self, Chroot};
+use crate::config::{Gc, Size};
+use crate::fs::{path_to_string, Lock};
+use crate::mount::bind_mount;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use sys_mount::{Mount, UnmountDrop};
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+macro_rules! err {
+    ($($args:expr),+) => {
+        return Err(Error::new(format!($($args),+)))
+    };
+}
+
+/// Locked for as long as the value is alive, as the Nix database can't be shared.
+pub struct SharedStore {
+    path: PathBuf,
+    lock: Lock,
+}
+
+impl SharedStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        if let Err(e) = std::fs::create_dir_all(&path) {
+            err!("Unable to create `{}`: {}", path_to_string(&path), e);
+        }
+
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(".lock");
+        let lock = match Lock::acquire(&lock_path) {
+            Ok(l) => l,
+            Err(e) => err!("{}", e),
+        };
+
+        Ok(Self { path, lock })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn lock(&self) -> &Lock {
+        &self.lock
+    }
+
+    pub fn mount(&self, rootfs: &Path) -> Result<UnmountDrop<Mount>> {
+        let target = rootfs.join("nix");
+        if let Err(e) = std::fs::create_dir_all(&target) {
+            err!("Unable to create `{}`: {}", path_to_string(&target), e);
+        }
+
+        match bind_mount(&self.path, &target, false) {
+            Ok(m) => Ok(m),
+            Err(e) => err!(
+                "Unable to bind-mount `{}` onto `{}`: {}",
+                path_to_string(&self.path),
+                path_to_string(&target),
+                e
+            ),
+        }
+    }
+
+    /// Counts hard-linked files once.
+    pub fn size(&self) -> Result<u64> {
+        let mut seen = std::collections::HashSet::new();
+
+        match disk_usage(&self.path, &mut seen) {
+            Ok(s) => Ok(s),
+            Err(e) => err!("Unable to measure `{}`: {}", path_to_string(&self.path), e),
+        }
+    }
+}
+
+pub struct Error {
+    error: String,
+}
+
+impl Error {
+    pub fn new<S: AsRef<str>>(error: S) -> Self {
+        let error = error.as_ref().to_owned();
+
+        Self { error }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+const PROFILES: &str = "var/nix/profiles";
+
+pub fn collect_garbage(chroot: &Chroot, store: &SharedStore, gc: &Gc, size: u64) -> Result<()> {
+    if let Some(age) = &gc.max_age_days {
+        let age = format!("{}d", age);
+        let profiles = match profiles(&store.path.join(PROFILES)) {
+            Ok(p) => p,
+            Err(e) => err!(
+                "Unable to list profiles of `{}`: {}",
+                path_to_string(&store.path),
+                e
+            ),
+        };
+
+        for profile in profiles {
+            let profile = format!("/nix/{}/{}", PROFILES, path_to_string(profile));
+            let args = ["nix-env", "-p", &profile, "--delete-generations", &age];
+            if let Err(e) = chroot::execute(chroot, args) {
+                err!(
+                    "Failed to delete generations of `{}` older than {}:\n{}",
+                    profile,
+                    age,
+                    e
+                );
+            }
+        }
+    }
+
+    if let Some(Size(max_size)) = gc.max_size {
+        if size > max_size {
+            let max_freed = (size - max_size).to_string();
+            if let Err(e) =
+                chroot::execute(chroot, ["nix-store", "--gc", "--max-freed", &max_freed])
+            {
+                err!("Failed to collect garbage:\n{}", e);
+            }
+        }
+    }
+
+    Ok({})
+}
+
+/// Skips their generations, `<profile>-<number>-link`.
+fn profiles(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut profiles = vec![];
+    if !dir.is_dir() {
+        return Ok(profiles);
+    }
+
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        for entry in std::fs::read_dir(dir.join(&relative))? {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+            let file_type = entry.file_type()?;
+
+            if file_type.is_dir() {
+                pending.push(path);
+            } else if file_type.is_symlink() && !is_generation(&entry.file_name().to_string_lossy())
+            {
+                profiles.push(path);
+            }
+        }
+    }
+    profiles.sort();
+
+    Ok(profiles)
+}
+
+fn is_generation(name: &str) -> bool {
+    let number = name
+        .strip_suffix("-link")
+        .and_then(|n| n.rsplit_once('-'))
+        .map(|(_, n)| n);
+
+    matches!(number, Some(n) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn disk_usage(
+    path: &Path,
+    seen: &mut std::collections::HashSet<(u64, u64)>,
+) -> std::io::Result<u64> {
+    let metadata = path.symlink_metadata()?;
+    if metadata.nlink() > 1 && !seen.insert((metadata.dev(), metadata.ino())) {
+        return Ok(0);
+    }
+
+    let mut size = metadata.blocks() * 512;
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            size += disk_usage(&entry?.path(), seen)?;
+        }
+    }
+
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn finds_profiles_but_not_their_generations() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("per-user/root");
+        std::fs::create_dir_all(&root).unwrap();
+        symlink("default-2-link", dir.path().join("default")).unwrap();
+        symlink("/nix/store/a", dir.path().join("default-1-link")).unwrap();
+        symlink("/nix/store/b", dir.path().join("default-2-link")).unwrap();
+        symlink("channels-1-link", root.join("channels")).unwrap();
+        symlink("/nix/store/c", root.join("channels-1-link")).unwrap();
+        symlink("/nix/store/d", root.join("my-profile-link")).unwrap();
+
+        assert_eq!(
+            profiles(dir.path()).unwrap(),
+            [
+                PathBuf::from("default"),
+                PathBuf::from("per-user/root/channels"),
+                PathBuf::from("per-user/root/my-profile-link"),
+            ]
+        );
+        assert!(profiles(&dir.path().join("missing")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn counts_hard_links_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, vec![1; 64 * 1024]).unwrap();
+        let once = SharedStore::open(dir.path())
+            .ok()
+            .unwrap()
+            .size()
+            .ok()
+            .unwrap();
+
+        std::fs::hard_link(&file, dir.path().join("link")).unwrap();
+        let twice = SharedStore::open(dir.path())
+            .ok()
+            .unwrap()
+            .size()
+            .ok()
+            .unwrap();
+
+        assert!(once >= 64 * 1024);
+        assert_eq!(once, twice);
+    }
+}
@@ -0,0 +1,85 @@
+use std::io::{Read, Result};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Token bucket limiting the combined throughput of everything sharing it.
+pub struct Throttle {
+    bytes_per_second: u64,
+    state: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_second: u64) -> Arc<Self> {
+        Arc::new(Self {
+            bytes_per_second: bytes_per_second.max(1),
+            state: Mutex::new(Bucket {
+                tokens: 0.0,
+                updated: Instant::now(),
+            }),
+        })
+    }
+
+    pub fn consume(&self, bytes: usize) {
+        let rate = self.bytes_per_second as f64;
+
+        let debt = {
+            let mut bucket = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.updated).as_secs_f64() * rate;
+            // Allow bursts of up to a second worth of data after being idle
+            bucket.tokens = (bucket.tokens + refill).min(rate) - bytes as f64;
+            bucket.updated = now;
+
+            -bucket.tokens
+        };
+
+        if debt > 0.0 {
+            std::thread::sleep(Duration::from_secs_f64(debt / rate));
+        }
+    }
+}
+
+pub struct ThrottledReader<R> {
+    inner: R,
+    throttle: Arc<Throttle>,
+}
+
+impl<R: Read> ThrottledReader<R> {
+    pub fn new(inner: R, throttle: Arc<Throttle>) -> Self {
+        Self { inner, throttle }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        // Keep reads small, so that the limit is applied smoothly rather than in large bursts
+        let limit = buf.len().min(16 * 1024);
+        let read = self.inner.read(&mut buf[..limit])?;
+        self.throttle.consume(read);
+
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_throughput() {
+        let throttle = Throttle::new(100_000);
+        let mut reader = ThrottledReader::new(&[0u8; 50_000][..], throttle);
+
+        let started = Instant::now();
+        let mut buffer = vec![];
+        reader.read_to_end(&mut buffer).unwrap();
+
+        assert_eq!(buffer.len(), 50_000);
+        assert!(started.elapsed() >= Duration::from_millis(400));
+    }
+}